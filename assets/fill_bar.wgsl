@@ -0,0 +1,45 @@
+// Health bar / fill level, see fill_bar.rs
+#import "mesh2d_vertex_output.wgsl"
+#import "generated/fill_bar.wgsl"
+
+@group(1) @binding(0)
+var<uniform> bar: FillBarUniform;
+
+// Share of a segment left empty as a gap before the next one
+let SEGMENT_GAP = 0.1;
+
+// How far along the fill direction a point of the quad is, 0..1
+fn progress(uv: vec2<f32>) -> f32 {
+#ifdef FILL_LEFT_TO_RIGHT
+    return uv.x;
+#endif
+#ifdef FILL_RIGHT_TO_LEFT
+    return 1.0 - uv.x;
+#endif
+#ifdef FILL_BOTTOM_TO_TOP
+    return 1.0 - uv.y;
+#endif
+#ifdef FILL_TOP_TO_BOTTOM
+    return uv.y;
+#endif
+}
+
+@fragment
+fn fragment(input: VertexOutput) -> @location(0) vec4<f32> {
+    let t = progress(input.uv);
+    var color = bar.empty_color;
+    if (t < bar.trail) {
+        color = bar.trail_color;
+    }
+    if (t < bar.fill) {
+        color = bar.fill_color;
+    }
+
+    if (bar.segments > 1u) {
+        let segment = t * f32(bar.segments);
+        if (fract(segment) > 1.0 - SEGMENT_GAP && segment < f32(bar.segments - 1u)) {
+            color = vec4<f32>(0.0, 0.0, 0.0, 0.0);
+        }
+    }
+    return color;
+}
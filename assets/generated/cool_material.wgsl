@@ -0,0 +1,12 @@
+// Generated from `CoolMaterialUniformBuffer` by `UPDATE_WGSL=1 cargo test`, do not edit
+
+struct CoolMaterialUniformBuffer {
+    color: vec4<f32>,
+    position: array<vec4<f32>, 64>,
+    light_color: array<vec4<f32>, 64>,
+    light_count: u32,
+    shape: array<vec4<f32>, 64>,
+    orientation: array<vec4<f32>, 64>,
+    polygon: array<array<vec4<f32>, 4>, 64>,
+    programs: array<array<vec4<f32>, 32>, 8>,
+};
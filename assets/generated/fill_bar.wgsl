@@ -0,0 +1,10 @@
+// Generated from `FillBarUniform` by `UPDATE_WGSL=1 cargo test`, do not edit
+
+struct FillBarUniform {
+    fill_color: vec4<f32>,
+    empty_color: vec4<f32>,
+    trail_color: vec4<f32>,
+    fill: f32,
+    trail: f32,
+    segments: u32,
+};
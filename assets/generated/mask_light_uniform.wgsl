@@ -0,0 +1,7 @@
+// Generated from `MaskLightUniform` by `UPDATE_WGSL=1 cargo test`, do not edit
+
+struct MaskLightUniform {
+    world_min: vec2<f32>,
+    world_max: vec2<f32>,
+    programs: array<array<vec4<f32>, 32>, 8>,
+};
@@ -0,0 +1,7 @@
+// Generated from `MaskTextureUniform` by `UPDATE_WGSL=1 cargo test`, do not edit
+
+struct MaskTextureUniform {
+    color: vec4<f32>,
+    world_min: vec2<f32>,
+    world_max: vec2<f32>,
+};
@@ -0,0 +1,6 @@
+// Generated from `CoolMaterialUniformInput` by `UPDATE_WGSL=1 cargo test`, do not edit
+
+struct CoolMaterialUniformInput {
+    color: vec4<f32>,
+    time: f32,
+};
@@ -0,0 +1,6 @@
+// Generated from `CoolMaterialUniformData` by `UPDATE_WGSL=1 cargo test`, do not edit
+
+struct CoolMaterialUniformData {
+    color: vec4<f32>,
+    time: f32,
+};
@@ -0,0 +1,45 @@
+// Instanced version of my_material_b.wgsl, see sprite_instancing.rs
+#import bevy_sprite::mesh2d_view_bindings
+#import bevy_sprite::mesh2d_bindings
+
+@group(2) @binding(0)
+var texture: texture_2d<f32>;
+@group(2) @binding(1)
+var our_sampler: sampler;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+    // xyz position, w scale
+    @location(5) i_position_scale: vec4<f32>,
+    @location(6) i_color: vec4<f32>,
+    @location(7) i_time: f32,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) time: f32,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    let position = vertex.position * vertex.i_position_scale.w + vertex.i_position_scale.xyz;
+    var out: VertexOutput;
+    out.clip_position = view.view_proj * mesh.model * vec4<f32>(position, 1.0);
+    out.uv = vertex.uv;
+    out.color = vertex.i_color;
+    out.time = vertex.i_time;
+    return out;
+}
+
+// Same as fragment() in my_material_b.wgsl with the uniform replaced by the instance
+@fragment
+fn fragment(input: VertexOutput) -> @location(0) vec4<f32> {
+    var output_color = vec4<f32>(1.0, 1.0, 1.0, input.time);
+    output_color = output_color * textureSample(texture, our_sampler, input.uv);
+    output_color = output_color * input.color;
+    return output_color;
+}
@@ -0,0 +1,260 @@
+// The lighting math of the light mask, shared by every shader that draws one.
+// Import it with `#import mask::functions`, MaskFunctionsPlugin keeps it
+// loaded. mask_sample.rs mirrors these functions on the CPU, so changes here
+// have to be made there too. Circles are always compiled, the other shapes
+// only with their SHAPE_* shader def, see LightShapes in mask_shape.rs.
+#define_import_path mask::functions
+
+// Shapes return the value a falloff compares against the light's radius.
+// For a circle that is 4 * dist², so `radius` is the squared diameter.
+fn mask_circle(st: vec2<f32>, center: vec2<f32>) -> f32 {
+    let dist = st - center;
+    return dot(dist, dist) * 4.0;
+}
+
+// Falloffs are 0 inside the light and 1 outside
+// An empty band would divide by zero, so no softness is a hard edge
+fn mask_falloff_smooth(value: f32, radius: f32, softness: f32) -> f32 {
+    if (radius * softness <= 0.0) {
+        return step(radius, value);
+    }
+    return smoothstep(radius - (radius * softness), radius + (radius * softness), value);
+}
+
+fn mask_falloff_linear(value: f32, radius: f32, softness: f32) -> f32 {
+    if (radius * softness <= 0.0) {
+        return step(radius, value);
+    }
+    return clamp((value - (radius - (radius * softness))) / (2.0 * radius * softness), 0.0, 1.0);
+}
+
+fn mask_falloff_hard(value: f32, radius: f32) -> f32 {
+    return step(radius, value);
+}
+
+// The falloff picked by the FALLOFF_* shader def, smooth when none is set
+fn mask_falloff(value: f32, radius: f32, softness: f32) -> f32 {
+#ifdef FALLOFF_LINEAR
+    return mask_falloff_linear(value, radius, softness);
+#endif
+#ifdef FALLOFF_HARD
+    return mask_falloff_hard(value, radius);
+#endif
+#ifndef FALLOFF_LINEAR
+#ifndef FALLOFF_HARD
+    return mask_falloff_smooth(value, radius, softness);
+#endif
+#endif
+}
+
+// Coverage of a circular light packed as x, y, radius, softness
+fn mask_light(st: vec2<f32>, light: vec4<f32>) -> f32 {
+    return mask_falloff(mask_circle(st, light.xy), light.z, light.w);
+}
+
+// Shapes other than the circle are signed distances in the light's local
+// space, negative inside. The slot packing is described in mask_shape.rs
+fn mask_rounded_box(p: vec2<f32>, half_size: vec2<f32>, corner_radius: f32) -> f32 {
+    let q = abs(p) - half_size + corner_radius;
+    return length(max(q, vec2<f32>(0.0))) + min(max(q.x, q.y), 0.0) - corner_radius;
+}
+
+// Not exact away from the outline, but close enough for the soft band
+fn mask_ellipse(p: vec2<f32>, radii: vec2<f32>) -> f32 {
+    let k0 = length(p / radii);
+    if (k0 == 0.0) {
+        return -min(radii.x, radii.y);
+    }
+    let k1 = length(p / (radii * radii));
+    return k0 * (k0 - 1.0) / k1;
+}
+
+fn mask_capsule(p: vec2<f32>, half_length: f32, radius: f32) -> f32 {
+    let q = vec2<f32>(p.x - clamp(p.x, -half_length, half_length), p.y);
+    return length(q) - radius;
+}
+
+fn mask_ring(p: vec2<f32>, radius: f32, thickness: f32) -> f32 {
+    return abs(length(p) - radius) - thickness * 0.5;
+}
+
+// Vertex i is in polygon[i / 2], xy for even i and zw for odd
+fn mask_polygon(p: vec2<f32>, polygon: array<vec4<f32>, 4>, count: u32) -> f32 {
+    // only a var can be indexed with a runtime value
+    var vertices = polygon;
+    let first = vertices[0].xy;
+    var d = dot(p - first, p - first);
+    var s = 1.0;
+    var j = count - 1u;
+    for (var i = 0u; i < count; i = i + 1u) {
+        let packed_i = vertices[i / 2u];
+        let vi = select(packed_i.zw, packed_i.xy, i % 2u == 0u);
+        let packed_j = vertices[j / 2u];
+        let vj = select(packed_j.zw, packed_j.xy, j % 2u == 0u);
+        let e = vj - vi;
+        let w = p - vi;
+        let b = w - e * clamp(dot(w, e) / dot(e, e), 0.0, 1.0);
+        d = min(d, dot(b, b));
+        let c = vec3<bool>(p.y >= vi.y, p.y < vj.y, e.x * w.y > e.y * w.x);
+        if (all(c) || !any(c)) {
+            s = -s;
+        }
+        j = i;
+    }
+    return s * sqrt(d);
+}
+
+// The distance to the shape of kind shape.w. Every kind but the circle is
+// behind the shader def CoolMaterialKey pushes when a light has that kind, the
+// kinds left out are never inside
+fn mask_shape_distance(p: vec2<f32>, shape: vec4<f32>, polygon: array<vec4<f32>, 4>) -> f32 {
+    let kind = u32(shape.w);
+    // only reached for circles in a composite, which have a diameter of 1
+    if (kind == 0u) {
+        return length(p) - 0.5;
+    }
+#ifdef SHAPE_ROUNDED_BOX
+    if (kind == 1u) {
+        return mask_rounded_box(p, shape.xy, shape.z);
+    }
+#endif
+#ifdef SHAPE_ELLIPSE
+    if (kind == 2u) {
+        return mask_ellipse(p, shape.xy);
+    }
+#endif
+#ifdef SHAPE_CAPSULE
+    if (kind == 3u) {
+        return mask_capsule(p, shape.x, shape.y);
+    }
+#endif
+#ifdef SHAPE_RING
+    if (kind == 4u) {
+        return mask_ring(p, shape.x, shape.y);
+    }
+#endif
+#ifdef SHAPE_POLYGON
+    if (kind == 5u) {
+        return mask_polygon(p, polygon, u32(shape.x));
+    }
+#endif
+    return 1.0e9;
+}
+
+fn mask_smooth_union(a: f32, b: f32, k: f32) -> f32 {
+    let h = clamp(0.5 + 0.5 * (b - a) / k, 0.0, 1.0);
+    return mix(b, a, h) - k * h * (1.0 - h);
+}
+
+// Runs the first len instructions of a composite's program, see
+// mask_composite.rs for the layout
+fn mask_program_distance(p: vec2<f32>, program: array<vec4<f32>, 32>, len: u32) -> f32 {
+    // the program of a tree that failed to compile is empty and lights nothing
+    if (len == 0u) {
+        return 1.0e9;
+    }
+    var instructions = program;
+    var stack: array<f32, 8>;
+    var top = 0u;
+    let no_polygon = array<vec4<f32>, 4>();
+    for (var i = 0u; i < len; i = i + 1u) {
+        let a = instructions[i * 2u];
+        let b = instructions[i * 2u + 1u];
+        let op = u32(a.w);
+        if (op < 8u) {
+            let dist = p - b.xy;
+            let local = vec2<f32>(b.z * dist.x + b.w * dist.y, b.z * dist.y - b.w * dist.x);
+            stack[top] = mask_shape_distance(local, a, no_polygon);
+            top = top + 1u;
+        } else {
+            let second = stack[top - 1u];
+            let first = stack[top - 2u];
+            top = top - 1u;
+            var d = min(first, second);
+            if (op == 9u) {
+                d = max(first, -second);
+            }
+            if (op == 10u) {
+                d = max(first, second);
+            }
+            if (op == 11u) {
+                d = mask_smooth_union(first, second, a.x);
+            }
+            stack[top - 1u] = d;
+        }
+    }
+    return stack[0];
+}
+
+// Local position of st for the light in a slot
+fn mask_slot_local(st: vec2<f32>, position: vec4<f32>, orientation: vec4<f32>) -> vec2<f32> {
+    let dist = st - position.xy;
+    return vec2<f32>(dot(orientation.xy, dist), dot(orientation.zw, dist));
+}
+
+// Distances go through the same falloffs as the circle: with a radius of 1,
+// distance + 1 gives a band of +-softness around the outline
+fn mask_distance_falloff(distance: f32, softness: f32) -> f32 {
+    return mask_falloff(distance + 1.0, 1.0, softness);
+}
+
+// A cone along the local x axis, shape is (half angle, range, angular
+// softness). Lit only where both the end and the sides let light through
+fn mask_cone_light(p: vec2<f32>, shape: vec4<f32>, softness: f32) -> f32 {
+    let radial = mask_distance_falloff(length(p) - shape.y, softness);
+    let angular = mask_distance_falloff(abs(atan2(p.y, p.x)) - shape.x, shape.z);
+    return 1.0 - (1.0 - radial) * (1.0 - angular);
+}
+
+// Coverage of the light in one slot of the uniform arrays, circles go through
+// mask_light(). Composites need their program and use mask_composite_light()
+fn mask_slot_light(
+    st: vec2<f32>,
+    position: vec4<f32>,
+    shape: vec4<f32>,
+    orientation: vec4<f32>,
+    polygon: array<vec4<f32>, 4>,
+) -> f32 {
+    if (u32(shape.w) == 0u) {
+        return mask_light(st, position);
+    }
+    let local = mask_slot_local(st, position, orientation);
+#ifdef SHAPE_CONE
+    if (u32(shape.w) == 7u) {
+        return mask_cone_light(local, shape, position.w);
+    }
+#endif
+    return mask_distance_falloff(mask_shape_distance(local, shape, polygon), position.w);
+}
+
+// Coverage of a composite light, program is the chunk shape.y points at
+fn mask_composite_light(
+    st: vec2<f32>,
+    position: vec4<f32>,
+    shape: vec4<f32>,
+    orientation: vec4<f32>,
+    program: array<vec4<f32>, 32>,
+) -> f32 {
+    let local = mask_slot_local(st, position, orientation);
+    return mask_distance_falloff(mask_program_distance(local, program, u32(shape.x)), position.w);
+}
+
+// Combines fold one light's coverage into the mask color. Multiply darkens by
+// every light in turn, tinted it also adds the light's color where it shines
+fn mask_multiply(color: vec4<f32>, coverage: f32) -> vec4<f32> {
+    return color * coverage;
+}
+
+fn mask_multiply_tinted(color: vec4<f32>, coverage: f32, light_color: vec4<f32>) -> vec4<f32> {
+    return color * coverage + light_color * (1.0 - coverage);
+}
+
+// Min keeps the darkest coverage of all lights, with the color of that light
+// as the tint, and applies it once after the loop
+fn mask_apply_darkness(color: vec4<f32>, darkness: f32) -> vec4<f32> {
+    return color * darkness;
+}
+
+fn mask_apply_darkness_tinted(color: vec4<f32>, darkness: f32, tint: vec4<f32>) -> vec4<f32> {
+    return color * darkness + tint * (1.0 - darkness);
+}
@@ -0,0 +1,101 @@
+// Draws every light of a mask as a quad into the mask texture, see mask_texture.rs.
+// The lights use the same mask functions and shader defs as my_material_t_2.wgsl.
+#import mask::functions
+#import "generated/mask_light_uniform.wgsl"
+
+@group(0) @binding(0)
+var<uniform> mask_lights: MaskLightUniform;
+
+struct Vertex {
+    @builtin(vertex_index) index: u32,
+    // one slot of the mask's uniform arrays, see mask_shape.rs
+    @location(0) position: vec4<f32>,
+    @location(1) light_color: vec4<f32>,
+    @location(2) shape: vec4<f32>,
+    @location(3) orientation: vec4<f32>,
+    @location(4) polygon_0: vec4<f32>,
+    @location(5) polygon_1: vec4<f32>,
+    @location(6) polygon_2: vec4<f32>,
+    @location(7) polygon_3: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec2<f32>,
+    @location(1) position: vec4<f32>,
+    @location(2) light_color: vec4<f32>,
+    @location(3) shape: vec4<f32>,
+    @location(4) orientation: vec4<f32>,
+    @location(5) polygon_0: vec4<f32>,
+    @location(6) polygon_1: vec4<f32>,
+    @location(7) polygon_2: vec4<f32>,
+    @location(8) polygon_3: vec4<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    // two triangles, corners in -1..1
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, 1.0),
+    );
+    let radius = vertex.position.z;
+    // mask_circle() is 4 * dist², compared against radius * (1 + softness) at most.
+    // Other shapes carry their extent in z
+    var extent = sqrt(max(radius, radius + radius * vertex.position.w)) * 0.5;
+    if (u32(vertex.shape.w) != 0u) {
+        extent = radius;
+    }
+    let world_position = vertex.position.xy + corners[vertex.index] * extent;
+    let half_size = (mask_lights.world_max - mask_lights.world_min) * 0.5;
+    let center = (mask_lights.world_min + mask_lights.world_max) * 0.5;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>((world_position - center) / half_size, 0.0, 1.0);
+    out.world_position = world_position;
+    out.position = vertex.position;
+    out.light_color = vertex.light_color;
+    out.shape = vertex.shape;
+    out.orientation = vertex.orientation;
+    out.polygon_0 = vertex.polygon_0;
+    out.polygon_1 = vertex.polygon_1;
+    out.polygon_2 = vertex.polygon_2;
+    out.polygon_3 = vertex.polygon_3;
+    return out;
+}
+
+@fragment
+fn fragment(input: VertexOutput) -> @location(0) vec4<f32> {
+    var coverage = 1.0;
+#ifdef SHAPE_COMPOSITE
+    if (u32(input.shape.w) == 6u) {
+        coverage = mask_composite_light(
+            input.world_position,
+            input.position,
+            input.shape,
+            input.orientation,
+            mask_lights.programs[u32(input.shape.y)],
+        );
+    }
+#endif
+    if (u32(input.shape.w) != 6u) {
+        let polygon = array<vec4<f32>, 4>(input.polygon_0, input.polygon_1, input.polygon_2, input.polygon_3);
+        coverage = mask_slot_light(
+            input.world_position,
+            input.position,
+            input.shape,
+            input.orientation,
+            polygon,
+        );
+    }
+    let lit = 1.0 - coverage;
+    var color = vec3<f32>(0.0, 0.0, 0.0);
+#ifdef LIGHT_TINT
+    color = input.light_color.rgb * lit;
+#endif
+    return vec4<f32>(color, lit);
+}
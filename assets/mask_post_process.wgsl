@@ -0,0 +1,25 @@
+// Vertex stage of the full-screen mask pass, see mask_post_process.rs.
+// The fragment stage is fragment() of my_material_t_2.wgsl, both share the
+// VertexOutput of mesh2d_vertex_output.wgsl.
+#import "mesh2d_vertex_output.wgsl"
+
+struct MaskView {
+    inverse_view_proj: mat4x4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> mask_view: MaskView;
+
+// One triangle that covers the whole screen
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    let clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = clip_position;
+    out.world_position = mask_view.inverse_view_proj * clip_position;
+    out.world_normal = vec3<f32>(0.0, 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
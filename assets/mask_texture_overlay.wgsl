@@ -0,0 +1,22 @@
+// The overlay drawn from the mask texture, see MaskTextureMaterial in mask_texture.rs
+#import "mesh2d_vertex_output.wgsl"
+#import "generated/mask_texture_uniform.wgsl"
+
+@group(1) @binding(0)
+var<uniform> material: MaskTextureUniform;
+@group(1) @binding(1)
+var mask_texture: texture_2d<f32>;
+@group(1) @binding(2)
+var mask_sampler: sampler;
+
+@fragment
+fn fragment(input: VertexOutput) -> @location(0) vec4<f32> {
+    let size = material.world_max - material.world_min;
+    let uv = vec2<f32>(
+        (input.world_position.x - material.world_min.x) / size.x,
+        (material.world_max.y - input.world_position.y) / size.y,
+    );
+    let lights = textureSample(mask_texture, mask_sampler, uv);
+    let darkness = 1.0 - lights.a;
+    return vec4<f32>(material.color.rgb * darkness + lights.rgb, material.color.a * darkness);
+}
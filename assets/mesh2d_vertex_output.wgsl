@@ -0,0 +1,9 @@
+// What the vertex stage of bevy's 2d mesh pipeline hands to the fragment stage
+// of every Material2d here. Import it with `#import "mesh2d_vertex_output.wgsl"`.
+//https://github.com/bevyengine/bevy/blob/c2da7800e3671ad92e775529070a814d0bc2f5f8/crates/bevy_sprite/src/mesh2d/mesh2d.wgsl
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec4<f32>,
+    @location(1) world_normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+};
@@ -1,18 +1,8 @@
-//https://github.com/bevyengine/bevy/blob/c2da7800e3671ad92e775529070a814d0bc2f5f8/crates/bevy_sprite/src/mesh2d/mesh2d.wgsl
-struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
-    @location(0) world_position: vec4<f32>,
-    @location(1) world_normal: vec3<f32>,
-    @location(2) uv: vec2<f32>,
-};
-
-struct MyMat {
-    color: vec4<f32>,
-    time: f32,
-}
+#import "mesh2d_vertex_output.wgsl"
+#import "generated/my_material_b.wgsl"
 
 @group(1) @binding(0)
-var<uniform> uniform_data: MyMat;
+var<uniform> uniform_data: CoolMaterialUniformData;
 @group(1) @binding(1)
 var texture: texture_2d<f32>;
 @group(1) @binding(2)
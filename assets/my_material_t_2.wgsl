@@ -1,40 +1,79 @@
-//https://github.com/bevyengine/bevy/blob/c2da7800e3671ad92e775529070a814d0bc2f5f8/crates/bevy_sprite/src/mesh2d/mesh2d.wgsl
-struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
-    @location(0) world_position: vec4<f32>,
-    @location(1) world_normal: vec3<f32>,
-    @location(2) uv: vec2<f32>,
-};
+#import "mesh2d_vertex_output.wgsl"
 
+// The shader defs below are pushed by CoolMaterial::specialize, see CoolMaterialKey.
+// They bound the light loop, the arrays themselves are sized by MAX_LIGHTS.
+// The SHAPE_* defs pick the shapes compiled in, see LightShapes
+#ifdef MAX_LIGHTS_16
+let MAX_FIRES = 16;
+#endif
+#ifdef MAX_LIGHTS_32
+let MAX_FIRES = 32;
+#endif
+#ifdef MAX_LIGHTS_64
 let MAX_FIRES = 64;
+#endif
+#ifdef MAX_LIGHTS_128
+let MAX_FIRES = 128;
+#endif
 
-struct MyMat {
-    color: vec4<f32>,
-    position: array<vec4<f32>, MAX_FIRES>,
-}
+#import "generated/cool_material.wgsl"
+#import mask::functions
 
 @group(1) @binding(0)
-var<uniform> uniform_data: MyMat;
-
-fn circle(st: vec2<f32>, center: vec2<f32>, radius: f32) -> f32{
-    let dist = st-center;
-    let smoothness = 1.0;
-	return smoothstep(radius-(radius*smoothness),
-                         radius+(radius*smoothness),
-                         dot(dist,dist)*4.0);
-}
+var<uniform> uniform_data: CoolMaterialUniformBuffer;
 
 @fragment
 fn fragment(input: VertexOutput) -> @location(0) vec4<f32> {
+    var darkness = 1.0;
+    var tint = vec4<f32>(0.0, 0.0, 0.0, 0.0);
     var output_color = uniform_data.color;
-    for( var i: i32 = 0; i < MAX_FIRES; i= i +1) {
-        if (uniform_data.position[i].z == 0.0) {
-            continue;
+    let light_count = min(uniform_data.light_count, u32(MAX_FIRES));
+    for( var i: u32 = 0u; i < light_count; i= i +1u) {
+        let shape = uniform_data.shape[i];
+        // lights nothing unless one of the branches below takes the slot
+        var coverage = 1.0;
+#ifdef SHAPE_COMPOSITE
+        if (u32(shape.w) == 6u) {
+            coverage = mask_composite_light(
+                input.world_position.xy,
+                uniform_data.position[i],
+                shape,
+                uniform_data.orientation[i],
+                uniform_data.programs[u32(shape.y)],
+            );
+        }
+#endif
+        if (u32(shape.w) != 6u) {
+            coverage = mask_slot_light(
+                input.world_position.xy,
+                uniform_data.position[i],
+                shape,
+                uniform_data.orientation[i],
+                uniform_data.polygon[i],
+            );
         }
-    
-        output_color = output_color * ( circle (input.world_position.xy, uniform_data.position[i].xy, uniform_data.position[i].z) );
+#ifdef BLEND_MULTIPLY
+#ifdef LIGHT_TINT
+        output_color = mask_multiply_tinted(output_color, coverage, uniform_data.light_color[i]);
+#endif
+#ifndef LIGHT_TINT
+        output_color = mask_multiply(output_color, coverage);
+#endif
+#endif
+#ifdef BLEND_MIN
+        if (coverage < darkness) {
+            darkness = coverage;
+            tint = uniform_data.light_color[i];
+        }
+#endif
     }
+#ifdef BLEND_MIN
+#ifdef LIGHT_TINT
+    output_color = mask_apply_darkness_tinted(output_color, darkness, tint);
+#endif
+#ifndef LIGHT_TINT
+    output_color = mask_apply_darkness(output_color, darkness);
+#endif
+#endif
     return output_color;
 }
-
-
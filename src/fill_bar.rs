@@ -0,0 +1,379 @@
+// A fill-level material for health bars and the like.
+//
+// An entity with a `Handle<FillBarMaterial>`, a `Health` and a `DamageTrail`
+// shows its health as the filled part of the bar. When health drops, the lost
+// part stays visible in the trail color for a moment and then drains away.
+// Colors, segment count and direction live in the material; health and trail
+// change every frame, so they are extracted and written straight into the
+// material's uniform buffer instead of going through `Assets`, which would
+// rebuild the bind group. That buffer belongs to the material, so every bar
+// needs a material of its own: bars sharing a handle all show the health of
+// whichever was prepared last, which is logged once per handle.
+
+use bevy::{
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_resource::{
+            AsBindGroup, AsBindGroupShaderType, RenderPipelineDescriptor, ShaderRef, ShaderType,
+            SpecializedMeshPipelineError,
+        },
+        renderer::RenderQueue,
+        Extract, RenderApp, RenderStage,
+    },
+    sprite::{Material2d, Material2dKey, Material2dPlugin, RenderMaterials2d},
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    material_binding::{uniform_buffer, BindingErrorLog, UniformBindings},
+    wgsl_struct::wgsl_struct,
+};
+
+// Seconds the trail waits after a hit before it starts to drain
+const TRAIL_HOLD: f32 = 0.5;
+// Fill fraction the trail drains per second
+const TRAIL_SPEED: f32 = 0.5;
+
+pub struct FillBarPlugin;
+
+impl Plugin for FillBarPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(Material2dPlugin::<FillBarMaterial>::default())
+            .add_system(update_damage_trails);
+        app.sub_app_mut(RenderApp)
+            .add_system_to_stage(RenderStage::Extract, extract_fill_bars)
+            .add_system_to_stage(RenderStage::Prepare, prepare_fill_bars);
+    }
+}
+
+// 0 is empty, 1 is full
+#[derive(Component, Clone, Copy)]
+pub struct Health {
+    pub value: f32,
+}
+
+#[derive(Component, Clone, Copy)]
+pub struct DamageTrail {
+    pub value: f32,
+    hold: f32,
+    last_health: f32,
+}
+
+impl DamageTrail {
+    pub fn new(health: &Health) -> Self {
+        Self {
+            value: health.value,
+            hold: 0.0,
+            last_health: health.value,
+        }
+    }
+}
+
+// Which edge of the bar fills first
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum FillDirection {
+    #[default]
+    LeftToRight,
+    RightToLeft,
+    BottomToTop,
+    TopToBottom,
+}
+
+// Written into the uniform as a `FillBarUniform`
+#[derive(AsBindGroup, TypeUuid, Clone)]
+#[uuid = "0b7d54e2-8f3c-4a61-b9d5-3e8c1f2a6d47"]
+#[bind_group_data(FillBarKey)]
+#[uniform(0, FillBarUniform)]
+pub struct FillBarMaterial {
+    pub fill_color: Color,
+    pub empty_color: Color,
+    pub trail_color: Color,
+    // overwritten every frame from `Health` and `DamageTrail`
+    pub fill: f32,
+    pub trail: f32,
+    // 0 or 1 draws one continuous bar
+    pub segments: u32,
+    pub direction: FillDirection,
+}
+
+impl Default for FillBarMaterial {
+    fn default() -> Self {
+        Self {
+            fill_color: Color::rgb(0.1, 0.8, 0.2),
+            empty_color: Color::rgba(0.0, 0.0, 0.0, 0.5),
+            trail_color: Color::rgb(0.9, 0.9, 0.9),
+            fill: 1.0,
+            trail: 1.0,
+            segments: 0,
+            direction: FillDirection::LeftToRight,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct FillBarKey {
+    direction: FillDirection,
+}
+
+impl From<&FillBarMaterial> for FillBarKey {
+    fn from(material: &FillBarMaterial) -> Self {
+        Self {
+            direction: material.direction,
+        }
+    }
+}
+
+impl FillBarKey {
+    fn shader_defs(&self) -> Vec<String> {
+        vec![match self.direction {
+            FillDirection::LeftToRight => "FILL_LEFT_TO_RIGHT",
+            FillDirection::RightToLeft => "FILL_RIGHT_TO_LEFT",
+            FillDirection::BottomToTop => "FILL_BOTTOM_TO_TOP",
+            FillDirection::TopToBottom => "FILL_TOP_TO_BOTTOM",
+        }
+        .to_string()]
+    }
+}
+
+impl UniformBindings for FillBarMaterial {}
+
+impl Material2d for FillBarMaterial {
+    fn fragment_shader() -> ShaderRef {
+        "fill_bar.wgsl".into()
+    }
+
+    fn specialize(
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: Material2dKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            fragment
+                .shader_defs
+                .extend(key.bind_group_data.shader_defs());
+        }
+        Ok(())
+    }
+}
+
+wgsl_struct! {
+    // The uniform of a `FillBarMaterial`, also declared in fill_bar.wgsl
+    #[derive(Clone, ShaderType)]
+    pub struct FillBarUniform {
+        fill_color: Color,
+        empty_color: Color,
+        trail_color: Color,
+        fill: f32,
+        trail: f32,
+        segments: u32,
+    }
+}
+
+impl AsBindGroupShaderType<FillBarUniform> for FillBarMaterial {
+    fn as_bind_group_shader_type(&self, _images: &RenderAssets<Image>) -> FillBarUniform {
+        FillBarUniform {
+            fill_color: self.fill_color,
+            empty_color: self.empty_color,
+            trail_color: self.trail_color,
+            fill: self.fill,
+            trail: self.trail,
+            segments: self.segments,
+        }
+    }
+}
+
+fn update_damage_trails(time: Res<Time>, mut trail_query: Query<(&Health, &mut DamageTrail)>) {
+    for (health, mut trail) in trail_query.iter_mut() {
+        if health.value < trail.last_health {
+            trail.hold = TRAIL_HOLD;
+        }
+        trail.last_health = health.value;
+
+        if health.value >= trail.value {
+            // healing fills up right away
+            trail.value = health.value;
+        } else if trail.hold > 0.0 {
+            trail.hold -= time.delta_seconds();
+        } else {
+            trail.value = (trail.value - TRAIL_SPEED * time.delta_seconds()).max(health.value);
+        }
+    }
+}
+
+#[derive(Component)]
+struct ExtractedFillBar {
+    fill: f32,
+    trail: f32,
+}
+
+fn extract_fill_bars(
+    mut commands: Commands,
+    bar_query: Extract<
+        Query<(
+            Entity,
+            &Health,
+            Option<&DamageTrail>,
+            &Handle<FillBarMaterial>,
+        )>,
+    >,
+    mut users: Local<HashMap<Handle<FillBarMaterial>, Entity>>,
+    mut reported: Local<HashSet<Handle<FillBarMaterial>>>,
+) {
+    users.clear();
+    for (entity, health, trail, handle) in bar_query.iter() {
+        if let Some(other) = users.insert(handle.clone_weak(), entity) {
+            if reported.insert(handle.clone_weak()) {
+                warn!(
+                    "fill bars {:?} and {:?} share the material {:?}, both show one of them",
+                    other, entity, handle.id
+                );
+            }
+        }
+        let fill = health.value.clamp(0.0, 1.0);
+        commands
+            .get_or_spawn(entity)
+            .insert(ExtractedFillBar {
+                fill,
+                trail: trail.map_or(fill, |trail| trail.value.clamp(0.0, 1.0)),
+            })
+            .insert(handle.clone());
+    }
+}
+
+fn prepare_fill_bars(
+    materials: Res<RenderMaterials2d<FillBarMaterial>>,
+    bar_query: Query<(&ExtractedFillBar, &Handle<FillBarMaterial>)>,
+    render_queue: Res<RenderQueue>,
+    mut error_log: Local<BindingErrorLog<FillBarMaterial>>,
+) {
+    // fill and trail sit next to each other, one write covers both
+    let offset = FillBarUniform::METADATA.offset(3);
+    for (bar, handle) in bar_query.iter() {
+        if let Some(material) = materials.get(handle) {
+            let buffer = match uniform_buffer(material, 0) {
+                Ok(buffer) => buffer,
+                Err(err) => {
+                    error_log.report(handle, &err);
+                    continue;
+                }
+            };
+            render_queue.write_buffer(buffer, offset, bytemuck::cast_slice(&[bar.fill, bar.trail]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::{assert_generated_wgsl, assert_uniform_matches};
+    use bevy::{
+        render::render_resource::encase,
+        utils::{Duration, Instant},
+    };
+
+    #[test]
+    fn generated_wgsl_is_up_to_date() {
+        assert_generated_wgsl::<FillBarUniform>("generated/fill_bar.wgsl");
+    }
+
+    #[test]
+    fn uniform_layout_matches_shader() {
+        assert_uniform_matches::<FillBarUniform, 6>(
+            "fill_bar.wgsl",
+            0,
+            &FillBarKey::default().shader_defs(),
+        );
+    }
+
+    #[test]
+    fn trails_hold_then_drain_and_heal_at_once() {
+        let mut app = App::default();
+        app.init_resource::<Time>().add_system(update_damage_trails);
+        let health = Health { value: 1.0 };
+        let bar = app
+            .world
+            .spawn()
+            .insert(health)
+            .insert(DamageTrail::new(&health))
+            .id();
+        app.world
+            .resource_mut::<Time>()
+            .update_with_instant(Instant::now());
+        let step = |app: &mut App, seconds: f32| {
+            let now = app.world.resource::<Time>().last_update().unwrap();
+            app.world
+                .resource_mut::<Time>()
+                .update_with_instant(now + Duration::from_secs_f32(seconds));
+            app.update();
+            *app.world.get::<DamageTrail>(bar).unwrap()
+        };
+        let assert_close = |actual: f32, expected: f32| {
+            assert!(
+                (actual - expected).abs() < 1e-4,
+                "expected {}, got {}",
+                expected,
+                actual
+            );
+        };
+
+        app.world.get_mut::<Health>(bar).unwrap().value = 0.6;
+        let trail = step(&mut app, 0.1);
+        assert_close(trail.value, 1.0);
+        assert_close(trail.hold, TRAIL_HOLD - 0.1);
+
+        // still holding
+        let trail = step(&mut app, 0.3);
+        assert_close(trail.value, 1.0);
+        assert_close(trail.hold, TRAIL_HOLD - 0.4);
+
+        // the frame that runs out the hold doesn't drain yet
+        let trail = step(&mut app, 0.2);
+        assert_close(trail.value, 1.0);
+        let trail = step(&mut app, 0.2);
+        assert_close(trail.value, 1.0 - TRAIL_SPEED * 0.2);
+
+        // draining stops at the health
+        let trail = step(&mut app, 1.0);
+        assert_close(trail.value, 0.6);
+
+        // another hit while draining holds again
+        app.world.get_mut::<Health>(bar).unwrap().value = 0.5;
+        let trail = step(&mut app, 0.1);
+        assert_close(trail.value, 0.6);
+        assert_close(trail.hold, TRAIL_HOLD - 0.1);
+
+        // healing past the trail takes it along right away
+        app.world.get_mut::<Health>(bar).unwrap().value = 0.9;
+        let trail = step(&mut app, 0.1);
+        assert_close(trail.value, 0.9);
+    }
+
+    #[test]
+    fn material_writes_the_uniform_it_declares() {
+        let material = FillBarMaterial {
+            fill: 0.25,
+            trail: 0.75,
+            segments: 4,
+            ..default()
+        };
+        let uniform = material.as_bind_group_shader_type(&RenderAssets::default());
+        let mut buffer = encase::UniformBuffer::new(Vec::new());
+        buffer.write(&uniform).unwrap();
+        let bytes = buffer.into_inner();
+        assert_eq!(bytes.len() as u64, FillBarUniform::min_size().get());
+
+        // where prepare_fill_bars writes fill and trail
+        let offset = FillBarUniform::METADATA.offset(3) as usize;
+        let written: [f32; 2] = bytemuck::pod_read_unaligned(&bytes[offset..offset + 8]);
+        assert_eq!(written, [0.25, 0.75]);
+    }
+
+    #[test]
+    fn fill_and_trail_are_adjacent() {
+        let metadata = FillBarUniform::METADATA;
+        assert_eq!(metadata.offset(4), metadata.offset(3) + 4);
+    }
+}
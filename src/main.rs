@@ -3,31 +3,177 @@
 #![allow(clippy::too_many_arguments)]
 
 use bevy::{
+    asset::AssetServerSettings,
     prelude::{shape::Quad, *},
     reflect::TypeUuid,
     render::{
         camera::ScalingMode,
+        mesh::MeshVertexBufferLayout,
         render_resource::{AsBindGroup, ShaderRef},
         renderer::RenderQueue,
     },
     render::{render_resource::*, Extract, RenderApp, RenderStage},
-    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle, RenderMaterials2d},
+    sprite::{
+        Material2d, Material2dKey, Material2dPlugin, MaterialMesh2dBundle, RenderMaterials2d,
+    },
+    utils::{HashMap, HashSet},
     window::PresentMode,
 };
 
+mod mask_composite;
+mod mask_debug;
+mod mask_fade;
+mod mask_functions;
+mod mask_instance;
+mod mask_post_process;
+mod mask_sample;
+mod mask_scene;
+mod mask_shape;
+mod mask_source;
+mod mask_texture;
+mod mask_upload;
+mod mask_visibility;
+mod material_binding;
+#[cfg(test)]
+mod shader_layout;
+mod wgsl_struct;
+
+use mask_composite::{ShapeProgram, ShapeTree, EMPTY_SHAPE_PROGRAM, MAX_SHAPE_PROGRAMS};
+use mask_debug::MaskDebugPlugin;
+use mask_fade::{Easing, LightFade, LightFadePlugin, LightFaded, SlotFade};
+use mask_functions::MaskFunctionsPlugin;
+use mask_instance::MaskInstancePlugin;
+use mask_post_process::{MaskPostProcess, MaskPostProcessPlugin, ScreenMask};
+use mask_scene::{MaskScene, MaskSceneLoader};
+use mask_shape::{LightShape, LightShapes, POLYGON_VEC4S, SHAPE_CIRCLE, SHAPE_COMPOSITE};
+use mask_source::{LightBeam, LightSource, LightSourcePlugin};
+use mask_texture::{MaskTexture, MaskTextureMaterial, MaskTexturePlugin};
+use mask_upload::{MaskUploadBytes, MaskUploadCache, MaskUploadPlugin};
+use mask_visibility::{
+    EnteredLight, LeftLight, LightSourceExposure, MaskVisibility, MaskVisibilityPlugin,
+};
+use material_binding::{uniform_buffer, BindingErrorLog, UniformBindings};
+use wgsl_struct::wgsl_struct;
+
 pub const CLEAR: Color = Color::rgb(1.0, 1.0, 1.0);
 pub const HEIGHT: f32 = 600.0;
 pub const RESOLUTION: f32 = 4.0 / 3.0;
 
+// Also sizes the arrays in the shader through the MAX_LIGHTS_<n> shader def,
+// which my_material_t_2.wgsl only declares for these values
 const MAX_LIGHTS: usize = 64;
+const _: () = assert!(matches!(MAX_LIGHTS, 16 | 32 | 64 | 128));
+const DEFAULT_SOFTNESS: f32 = 1.0;
+const OVERLAY_SIZE: Vec2 = Vec2::new(1.0, 1.0);
+const MASK_MODE: MaskMode = MaskMode::Overlay;
+
+// How the mask reaches the screen
+#[allow(dead_code)]
+enum MaskMode {
+    // an overlay quad that evaluates every light per pixel
+    Overlay,
+    // the same, over the whole camera output instead of a quad
+    PostProcess,
+    // an overlay quad that samples the lights rasterized into the mask texture
+    Rasterized,
+}
 
 #[derive(AsBindGroup, TypeUuid, Clone)]
 #[uuid = "f690fdae-d598-45ab-8225-97e2a3f056e0"]
+#[bind_group_data(CoolMaterialKey)]
 pub struct CoolMaterial {
     #[uniform(0)]
     color: Color,
     #[uniform(0)]
     position: [Vec4; MAX_LIGHTS],
+    #[uniform(0)]
+    light_color: [Color; MAX_LIGHTS],
+    #[uniform(0)]
+    light_count: u32,
+    #[uniform(0)]
+    shape: [Vec4; MAX_LIGHTS],
+    #[uniform(0)]
+    orientation: [Vec4; MAX_LIGHTS],
+    #[uniform(0)]
+    polygon: [[Vec4; POLYGON_VEC4S]; MAX_LIGHTS],
+    #[uniform(0)]
+    programs: [ShapeProgram; MAX_SHAPE_PROGRAMS],
+    falloff: MaskFalloff,
+    blend: MaskBlend,
+    tint: bool,
+}
+
+// How a light fades from its center to its edge
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum MaskFalloff {
+    #[default]
+    Smooth,
+    Linear,
+    Hard,
+}
+
+// How overlapping lights are combined
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum MaskBlend {
+    // every light cuts further into the overlay
+    #[default]
+    Multiply,
+    // only the strongest light at a point counts
+    Min,
+}
+
+// Everything that changes the compiled shader rather than the uniform
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CoolMaterialKey {
+    falloff: MaskFalloff,
+    blend: MaskBlend,
+    tint: bool,
+    // the kinds of the lit slots, changes as lights come and go
+    shapes: LightShapes,
+}
+
+impl Default for CoolMaterialKey {
+    fn default() -> Self {
+        CoolMaterialKey::from(&CoolMaterial::default())
+    }
+}
+
+impl From<&CoolMaterial> for CoolMaterialKey {
+    fn from(material: &CoolMaterial) -> Self {
+        Self {
+            falloff: material.falloff,
+            blend: material.blend,
+            tint: material.tint,
+            shapes: LightShapes::of_slots(
+                &material.shape[..(material.light_count as usize).min(MAX_LIGHTS)],
+                &material.programs,
+            ),
+        }
+    }
+}
+
+impl CoolMaterialKey {
+    fn shader_defs(&self) -> Vec<String> {
+        let mut shader_defs = vec![
+            format!("MAX_LIGHTS_{}", MAX_LIGHTS),
+            match self.falloff {
+                MaskFalloff::Smooth => "FALLOFF_SMOOTH",
+                MaskFalloff::Linear => "FALLOFF_LINEAR",
+                MaskFalloff::Hard => "FALLOFF_HARD",
+            }
+            .to_string(),
+            match self.blend {
+                MaskBlend::Multiply => "BLEND_MULTIPLY",
+                MaskBlend::Min => "BLEND_MIN",
+            }
+            .to_string(),
+        ];
+        if self.tint {
+            shader_defs.push("LIGHT_TINT".to_string());
+        }
+        shader_defs.extend(self.shapes.shader_defs());
+        shader_defs
+    }
 }
 
 // create n amount of vec4s using macro
@@ -42,37 +188,356 @@ impl Default for CoolMaterial {
         Self {
             color: Color::rgba(0.0, 0.0, 0.0, 0.0),
             position: vec4s!(MAX_LIGHTS),
+            light_color: [Color::rgba(0.0, 0.0, 0.0, 0.0); MAX_LIGHTS],
+            light_count: 0,
+            shape: [EMPTY_LIGHT_SHAPE; MAX_LIGHTS],
+            orientation: [EMPTY_LIGHT_ORIENTATION; MAX_LIGHTS],
+            polygon: [EMPTY_LIGHT_POLYGON; MAX_LIGHTS],
+            programs: [EMPTY_SHAPE_PROGRAM; MAX_SHAPE_PROGRAMS],
+            falloff: MaskFalloff::Smooth,
+            blend: MaskBlend::Multiply,
+            tint: true,
         }
     }
 }
 
+impl UniformBindings for CoolMaterial {}
+
 impl Material2d for CoolMaterial {
     fn fragment_shader() -> ShaderRef {
         "my_material_t_2.wgsl".into()
     }
+
+    fn specialize(
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: Material2dKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            fragment
+                .shader_defs
+                .extend(key.bind_group_data.shader_defs());
+        }
+        Ok(())
+    }
 }
 
-#[derive(Clone, ShaderType)]
-struct CoolMaterialUniformBuffer {
-    color: Color,
-    position: [Vec4; MAX_LIGHTS],
+wgsl_struct! {
+    #[derive(Clone, ShaderType)]
+    struct CoolMaterialUniformBuffer {
+        color: Color,
+        // x, y, radius, softness
+        position: [Vec4; MAX_LIGHTS],
+        light_color: [Color; MAX_LIGHTS],
+        // the active lights are packed into the first light_count slots
+        light_count: u32,
+        // shape parameters, the kind of shape in w, see mask_shape.rs
+        shape: [Vec4; MAX_LIGHTS],
+        // world to local 2x2 matrix of the shape, row major
+        orientation: [Vec4; MAX_LIGHTS],
+        // polygon vertices, two per vec4
+        polygon: [[Vec4; POLYGON_VEC4S]; MAX_LIGHTS],
+        // compiled shape trees, a composite light has its chunk in shape.y
+        programs: [ShapeProgram; MAX_SHAPE_PROGRAMS],
+    }
 }
 
-#[derive(Component, Clone, Copy)]
+impl From<&CoolMaterialUniformInput> for CoolMaterialUniformBuffer {
+    fn from(input: &CoolMaterialUniformInput) -> Self {
+        Self {
+            color: input.color,
+            position: input.position,
+            light_color: input.light_color,
+            light_count: input.light_count,
+            shape: input.shape,
+            orientation: input.orientation,
+            polygon: input.polygon,
+            programs: input.programs,
+        }
+    }
+}
+
+// position is packed as (x, y, radius, softness). The active lights are kept
+// in the first light_count slots, the shader does not look past them. The last
+// source_lights of those belong to `LightSource` entities and are rewritten
+// every frame, lights pushed by hand stay in front of them. Composite lights
+// keep their program in one of the programs chunks, which stay put when the
+// lights move between slots. A pushed light that is fading keeps its fade and
+// full size in `fades`, which moves along with it.
+#[derive(Component, Clone, Copy, PartialEq)]
 struct CoolMaterialUniformInput {
     color: Color,
     position: [Vec4; MAX_LIGHTS],
+    light_color: [Color; MAX_LIGHTS],
+    light_count: u32,
+    shape: [Vec4; MAX_LIGHTS],
+    orientation: [Vec4; MAX_LIGHTS],
+    polygon: [[Vec4; POLYGON_VEC4S]; MAX_LIGHTS],
+    programs: [ShapeProgram; MAX_SHAPE_PROGRAMS],
+    // not uploaded
+    source_lights: u32,
+    fades: [Option<SlotFade>; MAX_LIGHTS],
 }
 
 impl Default for CoolMaterialUniformInput {
     fn default() -> Self {
         Self {
             color: Color::rgba(0.0, 0.0, 0.0, 0.0),
-            position: vec4s!(MAX_LIGHTS),
+            position: [EMPTY_LIGHT_POSITION; MAX_LIGHTS],
+            light_color: [EMPTY_LIGHT_COLOR; MAX_LIGHTS],
+            light_count: 0,
+            shape: [EMPTY_LIGHT_SHAPE; MAX_LIGHTS],
+            orientation: [EMPTY_LIGHT_ORIENTATION; MAX_LIGHTS],
+            polygon: [EMPTY_LIGHT_POLYGON; MAX_LIGHTS],
+            programs: [EMPTY_SHAPE_PROGRAM; MAX_SHAPE_PROGRAMS],
+            source_lights: 0,
+            fades: [None; MAX_LIGHTS],
+        }
+    }
+}
+
+const EMPTY_LIGHT_POSITION: Vec4 = Vec4::new(0.0, 0.0, 0.0, DEFAULT_SOFTNESS);
+const EMPTY_LIGHT_COLOR: Color = Color::rgba(0.0, 0.0, 0.0, 0.0);
+// a circle, which ignores the orientation
+const EMPTY_LIGHT_SHAPE: Vec4 = Vec4::ZERO;
+const EMPTY_LIGHT_ORIENTATION: Vec4 = Vec4::new(1.0, 0.0, 0.0, 1.0);
+const EMPTY_LIGHT_POLYGON: [Vec4; POLYGON_VEC4S] = [Vec4::ZERO; POLYGON_VEC4S];
+
+// Everything one light occupies in the uniform arrays
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct LightSlot {
+    position: Vec4,
+    color: Color,
+    shape: Vec4,
+    orientation: Vec4,
+    polygon: [Vec4; POLYGON_VEC4S],
+    program: ShapeProgram,
+}
+
+impl LightSlot {
+    // The original light, a circle packed as (x, y, radius, softness)
+    fn circle(position: Vec4, color: Color) -> Self {
+        Self {
+            position,
+            color,
+            shape: EMPTY_LIGHT_SHAPE,
+            orientation: EMPTY_LIGHT_ORIENTATION,
+            polygon: EMPTY_LIGHT_POLYGON,
+            program: EMPTY_SHAPE_PROGRAM,
+        }
+    }
+
+    fn is_composite(&self) -> bool {
+        self.shape.w as u32 == SHAPE_COMPOSITE
+    }
+
+    // The light shrunk around its center to `factor` of its size. Circles keep
+    // their relative softness, the other shapes shrink their local space, and
+    // with it the softness. A shape can't shrink to nothing without losing its
+    // inverse orientation, so it stops far below a pixel
+    fn scaled(&self, factor: f32) -> Self {
+        let mut light = *self;
+        if self.shape.w as u32 == SHAPE_CIRCLE {
+            // the shader compares against the squared diameter
+            light.position.z *= factor * factor;
+        } else {
+            let factor = factor.max(MIN_LIGHT_SCALE);
+            light.position.z *= factor;
+            light.orientation /= factor;
+        }
+        light
+    }
+}
+
+const MIN_LIGHT_SCALE: f32 = 1e-4;
+
+impl CoolMaterialUniformInput {
+    // Every active light, in slot order
+    fn lights(&self) -> impl Iterator<Item = LightSlot> + '_ {
+        (0..self.light_count as usize).map(|slot| self.slot(slot))
+    }
+
+    // The lights pushed by hand, without the ones of `LightSource` entities.
+    // Fading lights come at their full size
+    fn own_lights(&self) -> impl Iterator<Item = LightSlot> + '_ {
+        (0..self.own_light_count()).map(|slot| match self.fades[slot] {
+            Some(fade) => fade.light,
+            None => self.slot(slot),
+        })
+    }
+
+    fn own_light_count(&self) -> usize {
+        (self.light_count - self.source_lights) as usize
+    }
+
+    fn slot(&self, slot: usize) -> LightSlot {
+        let mut light = LightSlot {
+            position: self.position[slot],
+            color: self.light_color[slot],
+            shape: self.shape[slot],
+            orientation: self.orientation[slot],
+            polygon: self.polygon[slot],
+            program: EMPTY_SHAPE_PROGRAM,
+        };
+        if light.is_composite() {
+            light.program = self.programs[light.shape.y as usize];
+            // where the program is kept is not part of the light
+            light.shape.y = 0.0;
+        }
+        light
+    }
+
+    // False when the light is a composite and every program chunk is taken
+    fn set_slot(&mut self, slot: usize, light: &LightSlot) -> bool {
+        let mut shape = light.shape;
+        if light.is_composite() {
+            let program = match self.free_program(slot) {
+                Some(program) => program,
+                None => return false,
+            };
+            self.programs[program] = light.program;
+            shape.y = program as f32;
+        }
+        self.position[slot] = light.position;
+        self.light_color[slot] = light.color;
+        self.shape[slot] = shape;
+        self.orientation[slot] = light.orientation;
+        self.polygon[slot] = light.polygon;
+        true
+    }
+
+    // A program chunk that no active light but the one in `slot` points at
+    fn free_program(&self, slot: usize) -> Option<usize> {
+        let used: Vec<usize> = (0..self.light_count as usize)
+            .filter(|other| *other != slot && self.shape[*other].w as u32 == SHAPE_COMPOSITE)
+            .map(|other| self.shape[other].y as usize)
+            .collect();
+        (0..MAX_SHAPE_PROGRAMS).find(|program| !used.contains(program))
+    }
+
+    fn copy_slots(&mut self, from: std::ops::Range<usize>, to: usize) {
+        self.position.copy_within(from.clone(), to);
+        self.light_color.copy_within(from.clone(), to);
+        self.shape.copy_within(from.clone(), to);
+        self.orientation.copy_within(from.clone(), to);
+        self.polygon.copy_within(from.clone(), to);
+        self.fades.copy_within(from, to);
+    }
+
+    // Adds a circle after the lights pushed so far, false when every slot is taken
+    fn push_light(&mut self, position: Vec4, color: Color) -> bool {
+        self.push_slot(&LightSlot::circle(position, color))
+    }
+
+    // Adds any light after the lights pushed so far, false when every slot or,
+    // for a composite, every program chunk is taken
+    fn push_slot(&mut self, light: &LightSlot) -> bool {
+        let count = self.light_count as usize;
+        if count >= MAX_LIGHTS {
+            return false;
+        }
+        // the source lights move up one slot to make room
+        let slot = self.own_light_count();
+        self.copy_slots(slot..count, slot + 1);
+        self.fades[slot] = None;
+        self.light_count += 1;
+        if !self.set_slot(slot, light) {
+            self.remove_light(slot);
+            return false;
+        }
+        true
+    }
+
+    // Removes the light in `slot` and moves the lights after it down by one,
+    // so the active lights stay packed at the front
+    fn remove_light(&mut self, slot: usize) {
+        let count = self.light_count as usize;
+        if slot >= count {
+            return;
+        }
+        if slot >= self.own_light_count() {
+            self.source_lights -= 1;
+        }
+        self.copy_slots(slot + 1..count, slot);
+        self.set_slot(
+            count - 1,
+            &LightSlot::circle(EMPTY_LIGHT_POSITION, EMPTY_LIGHT_COLOR),
+        );
+        self.fades[count - 1] = None;
+        self.light_count -= 1;
+    }
+
+    // Pushes a light like `push_slot` that grows from its center, see `fade_slot`
+    fn push_faded_in(&mut self, light: &LightSlot, fade: LightFade) -> bool {
+        if !self.push_slot(light) {
+            return false;
+        }
+        self.fade_slot(self.own_light_count() - 1, fade);
+        true
+    }
+
+    // Starts fading the light pushed into `slot`, from the size `fade` starts
+    // at. A fade out removes the light when done, see mask_fade.rs. Source
+    // lights fade with a `LightFade` on their entity instead
+    fn fade_slot(&mut self, slot: usize, fade: LightFade) {
+        if slot >= self.own_light_count() {
+            return;
         }
+        let light = match self.fades[slot] {
+            Some(slot_fade) => slot_fade.light,
+            None => self.slot(slot),
+        };
+        self.fades[slot] = Some(SlotFade { light, fade });
+        self.set_slot(slot, &light.scaled(fade.factor()));
+    }
+
+    fn slot_fade(&self, slot: usize) -> Option<LightFade> {
+        self.fades
+            .get(slot)
+            .copied()
+            .flatten()
+            .map(|slot_fade| slot_fade.fade)
+    }
+
+    // Replaces the lights of `LightSource` entities, returns where each of them
+    // went among the source lights, None for the ones that did not fit
+    fn set_source_lights(&mut self, lights: &[LightSlot]) -> Vec<Option<usize>> {
+        while self.source_lights > 0 {
+            self.remove_light(self.light_count as usize - 1);
+        }
+        lights
+            .iter()
+            .map(|light| {
+                let slot = self.light_count as usize;
+                if slot < MAX_LIGHTS && self.set_slot(slot, light) {
+                    self.light_count += 1;
+                    self.source_lights += 1;
+                    Some(self.source_lights as usize - 1)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn source_lights(&self) -> impl Iterator<Item = LightSlot> + '_ {
+        (self.own_light_count()..self.light_count as usize).map(|slot| self.slot(slot))
+    }
+
+    // The light at `index` among the source lights, which stays put when
+    // lights are pushed by hand
+    fn source_light(&self, index: usize) -> Option<LightSlot> {
+        let slot = self.own_light_count() + index;
+        (slot < self.light_count as usize).then(|| self.slot(slot))
     }
 }
 
+// Lights that were asked for but did not fit into the MAX_LIGHTS slots
+#[derive(Component, Clone, Default)]
+struct DroppedLights {
+    lights: Vec<LightSlot>,
+}
+
 fn main() {
     let mut app = App::new();
     app.insert_resource(ClearColor(CLEAR))
@@ -84,12 +549,33 @@ fn main() {
             resizable: false,
             ..Default::default()
         })
+        .insert_resource(AssetServerSettings {
+            watch_for_changes: true,
+            ..default()
+        })
         .add_plugins(DefaultPlugins)
         .add_plugin(Material2dPlugin::<CoolMaterial>::default())
-        .add_startup_system(spawn_camera)
+        .add_plugin(MaskDebugPlugin)
+        .add_plugin(MaskVisibilityPlugin)
+        .add_plugin(MaskUploadPlugin)
+        .add_plugin(MaskInstancePlugin)
+        .add_plugin(MaskPostProcessPlugin)
+        .add_plugin(MaskTexturePlugin)
+        .add_plugin(MaskFunctionsPlugin)
+        .add_plugin(LightSourcePlugin)
+        .add_plugin(LightFadePlugin)
+        .add_asset::<MaskScene>()
+        .init_asset_loader::<MaskSceneLoader>()
         // .add_plugin(ExtractResourcePlugin::<ExtractedTime>::default())
         .add_startup_system(setup)
-        .add_system(adjust_colordata_via_kb);
+        .add_system(adjust_colordata_via_kb)
+        .add_system(save_load_mask_scene_via_kb)
+        .add_system(mask_scene::apply_loaded_mask_scenes)
+        .add_system(log_light_events)
+        .add_system(sweep_guards)
+        .add_system(toggle_lanterns_via_kb)
+        .add_system(toggle_first_light_via_kb)
+        .add_system(log_spotted_probes);
     // Add all render world systems/resources
     app.sub_app_mut(RenderApp)
         .add_system_to_stage(RenderStage::Extract, extract_health)
@@ -102,25 +588,282 @@ fn setup(
     mut commands: Commands,
     mut mesh_assets: ResMut<Assets<Mesh>>,
     mut my_material_assets: ResMut<Assets<CoolMaterial>>,
+    mut mask_texture_materials: ResMut<Assets<MaskTextureMaterial>>,
+    mut mask_texture: ResMut<MaskTexture>,
+    assets: Res<AssetServer>,
 ) {
-    commands
-        .spawn_bundle(MaterialMesh2dBundle {
-            mesh: mesh_assets
-                .add(Mesh::from(shape::Quad::from(Quad {
-                    size: Vec2::new(1., 1.),
-                    ..Default::default()
-                })))
-                .into(),
-            material: my_material_assets.add(CoolMaterial {
-                ..Default::default()
-            }),
-            transform: Transform::from_xyz(0.0, 0.0, 0.0),
-            ..default()
-        })
-        .insert(CoolMaterialUniformInput {
+    let mask = (
+        CoolMaterialUniformInput {
             color: Color::rgba(0.0, 0.0, 0.0, 0.75),
             ..Default::default()
+        },
+        DroppedLights::default(),
+        assets.load::<MaskScene, _>(mask_scene::MASK_SCENE_ASSET),
+    );
+    let mask = match MASK_MODE {
+        MaskMode::Overlay => {
+            commands.spawn_bundle(camera_bundle());
+            commands
+                .spawn_bundle(MaterialMesh2dBundle {
+                    mesh: mesh_assets
+                        .add(Mesh::from(shape::Quad::from(Quad {
+                            size: OVERLAY_SIZE,
+                            ..Default::default()
+                        })))
+                        .into(),
+                    material: my_material_assets.add(CoolMaterial {
+                        ..Default::default()
+                    }),
+                    transform: Transform::from_xyz(0.0, 0.0, 0.0),
+                    ..default()
+                })
+                .insert_bundle(mask)
+                .id()
+        }
+        MaskMode::PostProcess => {
+            let mask = commands
+                .spawn_bundle(TransformBundle::default())
+                .insert(my_material_assets.add(CoolMaterial::default()))
+                .insert_bundle(mask)
+                .insert(ScreenMask)
+                .id();
+            commands
+                .spawn_bundle(camera_bundle())
+                .insert(MaskPostProcess { mask });
+            mask
+        }
+        MaskMode::Rasterized => {
+            commands.spawn_bundle(camera_bundle());
+            let material = MaskTextureMaterial::new(mask.0.color, &mask_texture);
+            commands
+                .spawn_bundle(MaterialMesh2dBundle {
+                    mesh: mesh_assets
+                        .add(Mesh::from(shape::Quad::from(Quad {
+                            size: OVERLAY_SIZE,
+                            ..Default::default()
+                        })))
+                        .into(),
+                    material: mask_texture_materials.add(material),
+                    ..default()
+                })
+                .insert_bundle(mask)
+                .id()
+        }
+    };
+    // the same lights, for materials that sample the mask texture
+    mask_texture.mask = Some(mask);
+
+    // Lights that follow their own entity, turned and stretched by its GlobalTransform
+    commands
+        .spawn_bundle(TransformBundle::from_transform(
+            Transform::from_xyz(-0.25, -0.2, 0.0).with_rotation(Quat::from_rotation_z(0.5)),
+        ))
+        .insert(LightSource::new(
+            mask,
+            LightShape::RoundedBox {
+                half_size: Vec2::new(0.08, 0.04),
+                corner_radius: 0.02,
+            },
+        ));
+    commands
+        .spawn_bundle(TransformBundle::from_transform(Transform::from_xyz(
+            0.25, -0.2, 0.0,
+        )))
+        .insert(LightSource::new(
+            mask,
+            LightShape::Polygon {
+                vertices: vec![
+                    Vec2::new(0.0, 0.08),
+                    Vec2::new(-0.07, -0.05),
+                    Vec2::new(0.07, -0.05),
+                ],
+            },
+        ));
+    // A room with a pillar in it, the pillar stays dark
+    commands
+        .spawn_bundle(TransformBundle::from_transform(Transform::from_xyz(
+            0.0, 0.25, 0.0,
+        )))
+        .insert(LightSource::new(
+            mask,
+            LightShape::Composite(
+                ShapeTree::shape(LightShape::RoundedBox {
+                    half_size: Vec2::new(0.15, 0.08),
+                    corner_radius: 0.01,
+                })
+                .subtract(
+                    ShapeTree::shape(LightShape::Ellipse {
+                        radii: Vec2::splat(0.03),
+                    })
+                    .at(Vec2::new(0.05, 0.0)),
+                ),
+            ),
+        ));
+    // A guard looking to the right, see `sweep_guards`
+    commands
+        .spawn_bundle(TransformBundle::from_transform(Transform::from_xyz(
+            -0.3, 0.05, 0.0,
+        )))
+        .insert(LightSource::new(
+            mask,
+            LightShape::Cone {
+                angle: 0.7,
+                range: 0.6,
+                angular_softness: 0.05,
+            },
+        ))
+        .insert(Guard)
+        .with_children(|guard| {
+            guard.spawn_bundle(lantern(mask));
         });
+    // A beam between two pylons, despawning either one turns it off
+    let pylons = [Vec3::new(-0.15, -0.35, 0.0), Vec3::new(0.15, -0.3, 0.0)].map(|at| {
+        commands
+            .spawn_bundle(TransformBundle::from_transform(
+                Transform::from_translation(at),
+            ))
+            .id()
+    });
+    commands
+        .spawn()
+        .insert(LightBeam::new(mask, pylons[0], pylons[1], 0.03));
+
+    // A small probe under the overlay that reports when a light reaches it
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: Color::rgb(1.0, 0.3, 0.0),
+                custom_size: Some(Vec2::new(0.03, 0.03)),
+                ..default()
+            },
+            transform: Transform::from_xyz(0.2, 0.0, -1.0),
+            ..default()
+        })
+        .insert(MaskVisibility::default());
+}
+
+#[derive(Component)]
+struct Guard;
+
+#[derive(Component)]
+struct Lantern;
+
+// A guard's lantern, carried to the side and swung along by the sweep
+fn lantern(mask: Entity) -> (TransformBundle, LightSource, Lantern) {
+    (
+        TransformBundle::from_transform(
+            Transform::from_xyz(0.0, 0.06, 0.0).with_scale(Vec3::splat(0.05)),
+        ),
+        LightSource::new(mask, LightShape::Circle),
+        Lantern,
+    )
+}
+
+// L fades the guards' lanterns out, and back in once they are gone
+fn toggle_lanterns_via_kb(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    guard_query: Query<(Entity, &LightSource, Option<&Children>), With<Guard>>,
+    mut lantern_query: Query<Option<&mut LightFade>, With<Lantern>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::L) {
+        return;
+    }
+    for (guard, source, children) in guard_query.iter() {
+        let children = children.map_or(&[][..], |children| &**children);
+        let carried = children
+            .iter()
+            .copied()
+            .find(|child| lantern_query.contains(*child));
+        match carried.map(|lantern| (lantern, lantern_query.get_mut(lantern).unwrap())) {
+            None => {
+                commands.entity(guard).with_children(|guard| {
+                    guard
+                        .spawn_bundle(lantern(source.mask))
+                        .insert(LightFade::fade_in(0.4, Easing::EaseOut));
+                });
+            }
+            // pressed again while fading, turn around from where it is
+            Some((_, Some(mut fade))) => *fade = fade.reversed(),
+            Some((lantern, None)) => {
+                commands
+                    .entity(lantern)
+                    .insert(LightFade::fade_out(0.4, Easing::EaseIn));
+            }
+        }
+    }
+}
+
+// K fades the first light pushed into each mask out, and the last one faded
+// back in once the mask has none left
+fn toggle_first_light_via_kb(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut colordata_query: Query<(Entity, &mut CoolMaterialUniformInput)>,
+    mut faded_out: Local<HashMap<Entity, LightSlot>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::K) {
+        return;
+    }
+    for (entity, mut colordata) in colordata_query.iter_mut() {
+        if let Some(fade) = colordata.slot_fade(0) {
+            colordata.fade_slot(0, fade.reversed());
+        } else if let Some(light) = colordata.own_lights().next() {
+            faded_out.insert(entity, light);
+            colordata.fade_slot(0, LightFade::fade_out(0.4, Easing::EaseIn));
+        } else if let Some(light) = faded_out.remove(&entity) {
+            colordata.push_faded_in(&light, LightFade::fade_in(0.4, Easing::EaseOut));
+        }
+    }
+}
+
+// Turns the guards' vision cones back and forth
+fn sweep_guards(time: Res<Time>, mut guard_query: Query<&mut Transform, With<Guard>>) {
+    let angle = (time.seconds_since_startup() as f32 * 0.8).sin() * 0.5;
+    for mut transform in guard_query.iter_mut() {
+        transform.rotation = Quat::from_rotation_z(angle);
+    }
+}
+
+// Asks every guard's cone about the probe, the same falloff the overlay draws with
+fn log_spotted_probes(
+    guard_query: Query<Entity, (With<Guard>, With<LightSource>)>,
+    probe_query: Query<(Entity, &GlobalTransform), With<MaskVisibility>>,
+    exposure: LightSourceExposure,
+    mut spotted: Local<HashSet<(Entity, Entity)>>,
+) {
+    for guard in guard_query.iter() {
+        for (probe, probe_transform) in probe_query.iter() {
+            let world_pos = probe_transform.translation().truncate();
+            let in_cone = exposure.of(guard, world_pos) > 0.5;
+            if in_cone && spotted.insert((guard, probe)) {
+                info!("{:?} spotted {:?}", guard, probe);
+            } else if !in_cone && spotted.remove(&(guard, probe)) {
+                info!("{:?} lost sight of {:?}", guard, probe);
+            }
+        }
+    }
+}
+
+fn log_light_events(
+    mut entered_events: EventReader<EnteredLight>,
+    mut left_events: EventReader<LeftLight>,
+    mut faded_events: EventReader<LightFaded>,
+) {
+    for event in entered_events.iter() {
+        info!("{:?} entered the light", event.entity);
+    }
+    for event in left_events.iter() {
+        info!("{:?} left the light", event.entity);
+    }
+    for event in faded_events.iter() {
+        match event.slot {
+            Some(slot) => info!(
+                "light {} of {:?} faded {:?}",
+                slot, event.entity, event.direction
+            ),
+            None => info!("{:?} faded {:?}", event.entity, event.direction),
+        }
+    }
 }
 
 fn extract_health(
@@ -139,25 +882,44 @@ fn prepare_my_material(
     materials: Res<RenderMaterials2d<CoolMaterial>>,
     health_query: Query<(&CoolMaterialUniformInput, &Handle<CoolMaterial>)>,
     render_queue: Res<RenderQueue>,
+    mut upload_cache: ResMut<MaskUploadCache>,
+    uploaded_bytes: Res<MaskUploadBytes>,
+    mut error_log: Local<BindingErrorLog<CoolMaterial>>,
 ) {
+    upload_cache.retain(|handle| materials.contains_key(handle));
+
     for (material_input, handle) in health_query.iter() {
         if let Some(material) = materials.get(handle) {
-            let binding = &material.bindings[0];
-            if let OwnedBindingResource::Buffer(cur_buffer) = binding {
-                let mut buffer = encase::UniformBuffer::new(Vec::new());
-                buffer
-                    .write(&CoolMaterialUniformBuffer {
-                        color: material_input.color,
-                        position: material_input.position,
-                    })
-                    .unwrap();
-                render_queue.write_buffer(cur_buffer, 0, buffer.as_ref());
+            let cur_buffer = match uniform_buffer(material, 0) {
+                Ok(buffer) => buffer,
+                Err(err) => {
+                    error_log.report(handle, &err);
+                    continue;
+                }
+            };
+            if !upload_cache.needs_upload(handle, cur_buffer.id(), material_input) {
+                continue;
             }
+
+            upload_cache
+                .scratch
+                .write(&CoolMaterialUniformBuffer::from(material_input))
+                .unwrap();
+            let data: &[u8] = upload_cache.scratch.as_ref();
+            upload_cache.for_each_dirty_range(handle, cur_buffer.id(), material_input, |range| {
+                render_queue.write_buffer(
+                    cur_buffer,
+                    range.start,
+                    &data[range.start as usize..range.end as usize],
+                );
+                uploaded_bytes.add(range.end - range.start);
+            });
+            upload_cache.mark_uploaded(handle, cur_buffer.id(), material_input);
         }
     }
 }
 
-fn spawn_camera(mut commands: Commands) {
+fn camera_bundle() -> Camera2dBundle {
     let mut camera = Camera2dBundle::default();
 
     camera.projection.right = 1.0 * RESOLUTION;
@@ -168,7 +930,7 @@ fn spawn_camera(mut commands: Commands) {
 
     camera.projection.scaling_mode = ScalingMode::None;
 
-    commands.spawn_bundle(camera);
+    camera
 }
 
 const TIME_SKIP: f32 = 1. / 60.;
@@ -179,34 +941,43 @@ fn adjust_colordata_via_kb(
     mut colordata_query: Query<(&mut CoolMaterialUniformInput, &mut Transform)>,
 ) {
     for (mut colordata, mut transform) in colordata_query.iter_mut() {
-        if keyboard_input.pressed(KeyCode::A) {
-            colordata.position[0].x -= 0.01 * TIME_SKIP * SPEED;
-        } else if keyboard_input.pressed(KeyCode::D) {
-            colordata.position[0].x += 0.01 * TIME_SKIP * SPEED;
-        } else if keyboard_input.pressed(KeyCode::S) {
-            colordata.position[0].y -= 0.01 * TIME_SKIP * SPEED;
-        } else if keyboard_input.pressed(KeyCode::W) {
-            colordata.position[0].y += 0.01 * TIME_SKIP * SPEED;
-        } else if keyboard_input.pressed(KeyCode::Q) {
-            colordata.position[0].z -= 0.01 * TIME_SKIP * SPEED;
-            colordata.position[0].z = colordata.position[0].z.max(0.0);
-        } else if keyboard_input.pressed(KeyCode::E) {
-            colordata.position[0].z += 0.01 * TIME_SKIP * SPEED;
-        }
-
-        if keyboard_input.pressed(KeyCode::Numpad4) {
-            colordata.position[1].x -= 0.01 * TIME_SKIP * SPEED;
-        } else if keyboard_input.pressed(KeyCode::Numpad6) {
-            colordata.position[1].x += 0.01 * TIME_SKIP * SPEED;
-        } else if keyboard_input.pressed(KeyCode::Numpad2) {
-            colordata.position[1].y -= 0.01 * TIME_SKIP * SPEED;
-        } else if keyboard_input.pressed(KeyCode::Numpad8) {
-            colordata.position[1].y += 0.01 * TIME_SKIP * SPEED;
-        } else if keyboard_input.pressed(KeyCode::Numpad7) {
-            colordata.position[1].z -= 0.01 * TIME_SKIP * SPEED;
-            colordata.position[1].z = colordata.position[1].z.max(0.0);
-        } else if keyboard_input.pressed(KeyCode::Numpad9) {
-            colordata.position[1].z += 0.01 * TIME_SKIP * SPEED;
+        // a light shrunk to nothing is removed, the ones after it move up a slot
+        if colordata.own_light_count() >= 1 {
+            if keyboard_input.pressed(KeyCode::A) {
+                colordata.position[0].x -= 0.01 * TIME_SKIP * SPEED;
+            } else if keyboard_input.pressed(KeyCode::D) {
+                colordata.position[0].x += 0.01 * TIME_SKIP * SPEED;
+            } else if keyboard_input.pressed(KeyCode::S) {
+                colordata.position[0].y -= 0.01 * TIME_SKIP * SPEED;
+            } else if keyboard_input.pressed(KeyCode::W) {
+                colordata.position[0].y += 0.01 * TIME_SKIP * SPEED;
+            } else if keyboard_input.pressed(KeyCode::Q) {
+                colordata.position[0].z -= 0.01 * TIME_SKIP * SPEED;
+                if colordata.position[0].z <= 0.0 {
+                    colordata.remove_light(0);
+                }
+            } else if keyboard_input.pressed(KeyCode::E) {
+                colordata.position[0].z += 0.01 * TIME_SKIP * SPEED;
+            }
+        }
+
+        if colordata.own_light_count() >= 2 {
+            if keyboard_input.pressed(KeyCode::Numpad4) {
+                colordata.position[1].x -= 0.01 * TIME_SKIP * SPEED;
+            } else if keyboard_input.pressed(KeyCode::Numpad6) {
+                colordata.position[1].x += 0.01 * TIME_SKIP * SPEED;
+            } else if keyboard_input.pressed(KeyCode::Numpad2) {
+                colordata.position[1].y -= 0.01 * TIME_SKIP * SPEED;
+            } else if keyboard_input.pressed(KeyCode::Numpad8) {
+                colordata.position[1].y += 0.01 * TIME_SKIP * SPEED;
+            } else if keyboard_input.pressed(KeyCode::Numpad7) {
+                colordata.position[1].z -= 0.01 * TIME_SKIP * SPEED;
+                if colordata.position[1].z <= 0.0 {
+                    colordata.remove_light(1);
+                }
+            } else if keyboard_input.pressed(KeyCode::Numpad9) {
+                colordata.position[1].z += 0.01 * TIME_SKIP * SPEED;
+            }
         }
 
         if keyboard_input.pressed(KeyCode::Up) {
@@ -230,3 +1001,176 @@ fn adjust_colordata_via_kb(
     }
     // println!("");
 }
+
+// F5 writes every overlay and its lights to the scene file it was loaded
+// from, F9 restores them from there
+fn save_load_mask_scene_via_kb(
+    keyboard_input: Res<Input<KeyCode>>,
+    asset_server: Res<AssetServer>,
+    mut colordata_query: Query<(
+        Entity,
+        &Handle<MaskScene>,
+        &mut CoolMaterialUniformInput,
+        &mut Transform,
+        &mut DroppedLights,
+    )>,
+) {
+    let save = keyboard_input.just_pressed(KeyCode::F5);
+    if !save && !keyboard_input.just_pressed(KeyCode::F9) {
+        return;
+    }
+    for (entity, handle, mut colordata, mut transform, mut dropped) in colordata_query.iter_mut() {
+        let path = match asset_server.get_handle_path(handle) {
+            Some(asset_path) => mask_scene::scene_file(asset_path.path()),
+            None => {
+                warn!("{:?} has no scene file", entity);
+                continue;
+            }
+        };
+        if save {
+            let scene = MaskScene::from_input(&colordata, &transform, &dropped);
+            match scene.save(&path) {
+                Ok(()) => info!("saved mask scene to {}", path.display()),
+                Err(err) => error!("failed to save mask scene: {}", err),
+            }
+            continue;
+        }
+        match MaskScene::load(&path) {
+            Ok(scene) => scene.apply(&mut colordata, &mut transform, &mut dropped),
+            Err(err) => error!("failed to load {}: {}", path.display(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::{assert_generated_wgsl, assert_uniform_matches};
+
+    #[test]
+    fn generated_wgsl_is_up_to_date() {
+        assert_generated_wgsl::<CoolMaterialUniformBuffer>("generated/cool_material.wgsl");
+    }
+
+    #[test]
+    fn uniform_layout_matches_shader() {
+        for key in [
+            CoolMaterialKey::default(),
+            CoolMaterialKey {
+                falloff: MaskFalloff::Hard,
+                blend: MaskBlend::Min,
+                tint: false,
+                shapes: LightShapes::of_slots(
+                    &(0..8).map(|kind| Vec4::W * kind as f32).collect::<Vec<_>>(),
+                    &[],
+                ),
+            },
+        ] {
+            assert_uniform_matches::<CoolMaterialUniformBuffer, 8>(
+                "my_material_t_2.wgsl",
+                0,
+                &key.shader_defs(),
+            );
+        }
+    }
+
+    #[test]
+    fn shader_defs_follow_the_shapes() {
+        let mut input = CoolMaterialUniformInput::default();
+        let ring = LightShape::Ring {
+            radius: 0.3,
+            thickness: 0.1,
+        };
+        let tree = ShapeTree::shape(LightShape::Ellipse {
+            radii: Vec2::new(0.2, 0.1),
+        })
+        .union(ShapeTree::shape(LightShape::Circle));
+        assert!(input.push_light(Vec4::new(0.0, 0.0, 0.1, 1.0), Color::WHITE));
+        for shape in [ring, LightShape::Composite(tree)] {
+            assert!(input.push_slot(&shape.to_slot(Mat4::IDENTITY, 0.02, Color::WHITE)));
+        }
+        let material = CoolMaterial {
+            light_count: input.light_count,
+            shape: input.shape,
+            programs: input.programs,
+            ..default()
+        };
+
+        let shader_defs = CoolMaterialKey::from(&material).shader_defs();
+        let shape_defs: Vec<&str> = shader_defs
+            .iter()
+            .map(String::as_str)
+            .filter(|def| def.starts_with("SHAPE_"))
+            .collect();
+        // the ellipse only lives in the composite's program
+        assert_eq!(
+            shape_defs,
+            vec!["SHAPE_ELLIPSE", "SHAPE_RING", "SHAPE_COMPOSITE"]
+        );
+        let empty = CoolMaterialKey::default().shader_defs();
+        assert!(!empty.iter().any(|def| def.starts_with("SHAPE_")));
+    }
+
+    #[test]
+    fn removing_a_light_keeps_the_rest_packed() {
+        let mut input = CoolMaterialUniformInput::default();
+        for x in [1.0, 2.0, 3.0] {
+            assert!(input.push_light(Vec4::new(x, 0.0, 0.1, 1.0), Color::WHITE));
+        }
+        input.remove_light(0);
+        assert_eq!(input.light_count, 2);
+        let xs: Vec<f32> = input.lights().map(|light| light.position.x).collect();
+        assert_eq!(xs, vec![2.0, 3.0]);
+        assert_eq!(input.position[2], EMPTY_LIGHT_POSITION);
+    }
+
+    #[test]
+    fn push_light_stops_at_max_lights() {
+        let mut input = CoolMaterialUniformInput::default();
+        for _ in 0..MAX_LIGHTS {
+            assert!(input.push_light(Vec4::new(0.0, 0.0, 0.1, 1.0), Color::WHITE));
+        }
+        assert!(!input.push_light(Vec4::new(0.0, 0.0, 0.1, 1.0), Color::WHITE));
+        assert_eq!(input.light_count as usize, MAX_LIGHTS);
+    }
+
+    #[test]
+    fn source_lights_stay_behind_pushed_lights() {
+        let mut input = CoolMaterialUniformInput::default();
+        let source = |x| LightSlot::circle(Vec4::new(x, 0.0, 0.1, 1.0), Color::WHITE);
+        assert!(input.push_light(Vec4::new(1.0, 0.0, 0.1, 1.0), Color::WHITE));
+        assert_eq!(
+            input.set_source_lights(&[source(10.0), source(11.0)]),
+            vec![Some(0), Some(1)]
+        );
+        assert!(input.push_light(Vec4::new(2.0, 0.0, 0.1, 1.0), Color::WHITE));
+        let xs: Vec<f32> = input.lights().map(|light| light.position.x).collect();
+        assert_eq!(xs, vec![1.0, 2.0, 10.0, 11.0]);
+        assert_eq!(input.source_light(1).unwrap().position.x, 11.0);
+
+        input.set_source_lights(&[source(12.0)]);
+        let xs: Vec<f32> = input.lights().map(|light| light.position.x).collect();
+        assert_eq!(xs, vec![1.0, 2.0, 12.0]);
+        assert_eq!(input.own_light_count(), 2);
+    }
+
+    #[test]
+    fn composite_lights_share_the_program_chunks() {
+        let mut input = CoolMaterialUniformInput::default();
+        let composite = |x: f32| {
+            let tree = ShapeTree::shape(LightShape::Circle).at(Vec2::new(x, 0.0));
+            LightShape::Composite(tree).to_slot(Mat4::IDENTITY, 0.02, Color::WHITE)
+        };
+        for i in 0..MAX_SHAPE_PROGRAMS {
+            assert!(input.push_slot(&composite(i as f32)));
+        }
+        assert!(!input.push_slot(&composite(10.0)));
+        assert_eq!(input.light_count as usize, MAX_SHAPE_PROGRAMS);
+
+        input.remove_light(2);
+        assert!(input.push_slot(&composite(10.0)));
+        let lights: Vec<LightSlot> = input.lights().collect();
+        assert_eq!(lights[1], composite(1.0));
+        assert_eq!(lights[MAX_SHAPE_PROGRAMS - 1], composite(10.0));
+    }
+}
@@ -15,13 +15,27 @@ use bevy::{
         render_resource::*,
         Extract, RenderApp, RenderStage,
     },
-    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle, RenderMaterials2d},
+    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle, Mesh2dHandle, RenderMaterials2d},
     window::PresentMode,
 };
 
+mod fill_bar;
+mod material_binding;
+#[cfg(test)]
+mod shader_layout;
+mod sprite_instancing;
+mod wgsl_struct;
+
+use fill_bar::{DamageTrail, FillBarMaterial, FillBarPlugin, FillDirection, Health};
+use material_binding::{uniform_buffer, BindingErrorLog, UniformBindings};
+use sprite_instancing::{InstancedSprites, SpriteInstance, SpriteInstancingPlugin};
+use wgsl_struct::wgsl_struct;
+
 pub const CLEAR: Color = Color::rgb(0.3, 0.3, 0.3);
 pub const HEIGHT: f32 = 900.0;
 pub const RESOLUTION: f32 = 16.0 / 9.0;
+// Sprites per side of the instanced grid
+pub const BATCH_SIDE: usize = 20;
 
 #[derive(AsBindGroup, TypeUuid, Clone)]
 #[uuid = "f690fdae-d598-45ab-8225-97e2a3f056e0"]
@@ -35,21 +49,20 @@ pub struct CoolMaterial {
     image: Handle<Image>,
 }
 
+impl UniformBindings for CoolMaterial {}
+
 impl Material2d for CoolMaterial {
     fn fragment_shader() -> ShaderRef {
         "my_material_b.wgsl".into()
     }
 }
 
-#[derive(Clone, ShaderType)]
-struct CoolMaterialUniformData {
-    color: Color,
-    time: f32,
-}
-
-#[derive(Component, Clone, Copy)]
-struct Health {
-    value: f32,
+wgsl_struct! {
+    #[derive(Clone, ShaderType)]
+    struct CoolMaterialUniformData {
+        color: Color,
+        time: f32,
+    }
 }
 
 fn main() {
@@ -65,7 +78,10 @@ fn main() {
         })
         .add_plugins(DefaultPlugins)
         .add_plugin(Material2dPlugin::<CoolMaterial>::default())
+        .add_plugin(SpriteInstancingPlugin)
+        .add_plugin(FillBarPlugin)
         .add_startup_system(spawn_camera)
+        .add_system(change_health)
         .add_plugin(ExtractResourcePlugin::<ExtractedTime>::default())
         .add_startup_system(setup);
     // Add all render world systems/resources
@@ -80,6 +96,7 @@ fn setup(
     mut commands: Commands,
     mut mesh_assets: ResMut<Assets<Mesh>>,
     mut my_material_assets: ResMut<Assets<CoolMaterial>>,
+    mut fill_bar_assets: ResMut<Assets<FillBarMaterial>>,
     assets: Res<AssetServer>,
 ) {
     commands
@@ -116,6 +133,54 @@ fn setup(
             ..default()
         })
         .insert(Health { value: 0.8 });
+
+    // Health bars above the sprites, with the sprites' health
+    let bar_mesh: Mesh2dHandle = mesh_assets
+        .add(Mesh::from(shape::Quad::new(Vec2::new(0.5, 0.05))))
+        .into();
+    for (x, value, segments, direction) in [
+        (-0.6, 0.2, 5, FillDirection::LeftToRight),
+        (0.6, 0.8, 10, FillDirection::RightToLeft),
+    ] {
+        let health = Health { value };
+        commands
+            .spawn_bundle(MaterialMesh2dBundle {
+                mesh: bar_mesh.clone(),
+                material: fill_bar_assets.add(FillBarMaterial {
+                    segments,
+                    direction,
+                    ..default()
+                }),
+                transform: Transform::from_xyz(x, 0.32, 0.1),
+                ..default()
+            })
+            .insert(health)
+            .insert(DamageTrail::new(&health));
+    }
+
+    // One draw for the whole grid, health goes where the material has `time`
+    let instances = (0..BATCH_SIDE * BATCH_SIDE)
+        .map(|i| {
+            let (x, y) = ((i % BATCH_SIDE) as f32, (i / BATCH_SIDE) as f32);
+            let step = 2.0 / BATCH_SIDE as f32;
+            let health = (x + y) / (2 * BATCH_SIDE - 2) as f32;
+            SpriteInstance::new(
+                Vec3::new(-1.0 + step * (x + 0.5), -1.0 + step * (y + 0.5), -1.0),
+                step,
+                Color::rgb(1.0 - health, health, 0.3),
+                health,
+            )
+        })
+        .collect();
+    commands
+        .spawn_bundle(SpatialBundle::default())
+        .insert(Mesh2dHandle(
+            mesh_assets.add(Mesh::from(shape::Quad::new(Vec2::ONE))),
+        ))
+        .insert(InstancedSprites {
+            image: assets.load("awesome.png"),
+            instances,
+        });
 }
 
 struct ExtractedTime {
@@ -144,25 +209,45 @@ fn extract_health(
     }
 }
 
+// H damages and J heals every sprite and bar
+fn change_health(keyboard_input: Res<Input<KeyCode>>, mut health_query: Query<&mut Health>) {
+    let change = if keyboard_input.just_pressed(KeyCode::H) {
+        -0.1
+    } else if keyboard_input.just_pressed(KeyCode::J) {
+        0.1
+    } else {
+        return;
+    };
+    for mut health in health_query.iter_mut() {
+        health.value = (health.value + change).clamp(0.0, 1.0);
+    }
+}
+
 fn prepare_my_material(
     materials: Res<RenderMaterials2d<CoolMaterial>>,
     health_query: Query<(&Health, &Handle<CoolMaterial>)>,
     time: Res<ExtractedTime>,
     render_queue: Res<RenderQueue>,
+    mut error_log: Local<BindingErrorLog<CoolMaterial>>,
 ) {
     for (health, handle) in health_query.iter() {
         if let Some(material) = materials.get(handle) {
-            let binding = &material.bindings[2];
-            if let OwnedBindingResource::Buffer(cur_buffer) = binding {
-                let mut buffer = encase::UniformBuffer::new(Vec::new());
-                buffer
-                    .write(&CoolMaterialUniformData {
-                        color: Color::rgb(1.0, 1.0, 1.0),
-                        time: time.seconds_since_startup % 1.0,
-                    })
-                    .unwrap();
-                render_queue.write_buffer(cur_buffer, 0, buffer.as_ref());
-            }
+            let cur_buffer = match uniform_buffer(material, 0) {
+                Ok(buffer) => buffer,
+                Err(err) => {
+                    error_log.report(handle, &err);
+                    continue;
+                }
+            };
+            let mut buffer = encase::UniformBuffer::new(Vec::new());
+            buffer
+                .write(&CoolMaterialUniformData {
+                    // green when healthy, red when hurt, like the grid
+                    color: Color::rgb(1.0 - health.value, health.value, 0.3),
+                    time: time.seconds_since_startup % 1.0,
+                })
+                .unwrap();
+            render_queue.write_buffer(cur_buffer, 0, buffer.as_ref());
         }
     }
 }
@@ -180,3 +265,19 @@ fn spawn_camera(mut commands: Commands) {
 
     commands.spawn_bundle(camera);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::{assert_generated_wgsl, assert_uniform_matches};
+
+    #[test]
+    fn generated_wgsl_is_up_to_date() {
+        assert_generated_wgsl::<CoolMaterialUniformData>("generated/my_material_b.wgsl");
+    }
+
+    #[test]
+    fn uniform_layout_matches_shader() {
+        assert_uniform_matches::<CoolMaterialUniformData, 2>("my_material_b.wgsl", 0, &[]);
+    }
+}
@@ -7,17 +7,22 @@ use bevy::{
     reflect::TypeUuid,
     render::{
         camera::ScalingMode,
-        render_resource::{
-            encase::{self},
-            AsBindGroup, OwnedBindingResource, ShaderRef, ShaderType,
-        },
-        renderer::RenderQueue,
-        Extract, RenderApp, RenderStage,
+        render_resource::{AsBindGroup, ShaderRef, ShaderType},
     },
-    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle, RenderMaterials2d},
+    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle},
     window::PresentMode,
 };
 
+mod material_binding;
+#[cfg(test)]
+mod shader_layout;
+mod uniform_bridge;
+mod wgsl_struct;
+
+use material_binding::UniformBindings;
+use uniform_bridge::UniformBridgePlugin;
+use wgsl_struct::wgsl_struct;
+
 pub const CLEAR: Color = Color::rgb(1.0, 1.0, 1.0);
 pub const HEIGHT: f32 = 600.0;
 pub const RESOLUTION: f32 = 4.0 / 3.0;
@@ -36,13 +41,9 @@ fn main() {
         })
         .add_plugins(DefaultPlugins)
         .add_plugin(Material2dPlugin::<CoolMaterial>::default())
+        .add_plugin(UniformBridgePlugin::<CoolMaterialUniformInput, CoolMaterial>::default())
         .add_startup_system(spawn_camera)
-        // .add_system(adjust_colordata_via_kb)
-        .add_system(setup_shader);
-
-    // app.sub_app_mut(RenderApp)
-    // .add_system_to_stage(RenderStage::Extract, extract_data_to_cool_material)
-    // .add_system_to_stage(RenderStage::Prepare, prepare_cool_material);
+        .add_startup_system(setup_shader);
 
     app.run();
 }
@@ -61,22 +62,16 @@ fn spawn_camera(mut commands: Commands) {
     commands.spawn_bundle(camera);
 }
 
-// This will be serialized into a buffer
-#[derive(ShaderType, Clone)]
-struct CoolMaterialUniformBuffer {
-    color: Color,
-    time: f32,
-    // position: [Vec4; 10],
-    // size: [f32; 2],
-}
-
-// This is what we will be interacing with
-#[derive(Component, Clone, Copy)]
-struct CoolMaterialUniformInput {
-    color: Color,
-    time: f32,
-    // position: [Vec4; 10],
-    // size: [f32; 2],
+// This is what we will be interacing with, it is serialized into the
+// material's buffer as is
+wgsl_struct! {
+    #[derive(Component, ShaderType, Clone, Copy)]
+    struct CoolMaterialUniformInput {
+        color: Color,
+        time: f32,
+        // position: [Vec4; 10],
+        // size: [f32; 2],
+    }
 }
 
 impl Default for CoolMaterialUniformInput {
@@ -138,6 +133,8 @@ impl Default for CoolMaterial {
     }
 }
 
+impl UniformBindings for CoolMaterial {}
+
 impl Material2d for CoolMaterial {
     fn fragment_shader() -> ShaderRef {
         "my_material_t.wgsl".into()
@@ -167,63 +164,18 @@ fn setup_shader(
         .insert(CoolMaterialUniformInput::default());
 }
 
-fn extract_data_to_cool_material(
-    mut commands: Commands,
-    colordata_query: Extract<Query<(Entity, &CoolMaterialUniformInput, &Handle<CoolMaterial>)>>,
-) {
-    for (entity, colordata, handle) in colordata_query.iter() {
-        commands
-            .get_or_spawn(entity)
-            .insert(*colordata)
-            .insert(handle.clone());
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::{assert_generated_wgsl, assert_uniform_matches};
 
-fn prepare_cool_material(
-    materials: Res<RenderMaterials2d<CoolMaterial>>,
-    colordata_query: Query<(&CoolMaterialUniformInput, &Handle<CoolMaterial>)>,
-    render_queue: Res<RenderQueue>,
-) {
-    for (colordata, handle) in &colordata_query {
-        if let Some(material) = materials.get(handle) {
-            let binding = &material.bindings[0];
-            if let OwnedBindingResource::Buffer(cur_buffer) = binding {
-                let mut buffer = encase::UniformBuffer::new(Vec::new());
-                // get color from cool material
-                // buffer
-                //     .write(&CoolMaterialUniformData {
-                //         color: colordata.color,
-                //         time: colordata.time,
-                //         position: colordata.position,
-                //         // size: colordata.size,
-                //     })
-                //     .unwrap();
-
-                render_queue.write_buffer(cur_buffer, 0, buffer.as_ref());
-            }
-        }
+    #[test]
+    fn generated_wgsl_is_up_to_date() {
+        assert_generated_wgsl::<CoolMaterialUniformInput>("generated/my_material_t.wgsl");
     }
-}
 
-fn adjust_colordata_via_kb(
-    keyboard_input: Res<Input<KeyCode>>,
-    mut colordata_query: Query<&mut CoolMaterialUniformInput>,
-) {
-    for mut colordata in colordata_query.iter_mut() {
-        // if keyboard_input.pressed(KeyCode::A) {
-        //     colordata.position[0].x -= 0.01;
-        // } else if keyboard_input.pressed(KeyCode::D) {
-        //     colordata.position[0].x += 0.01;
-        // } else if keyboard_input.pressed(KeyCode::S) {
-        //     colordata.position[0].y -= 0.01;
-        // } else if keyboard_input.pressed(KeyCode::W) {
-        //     colordata.position[0].y += 0.01;
-        // } else if keyboard_input.pressed(KeyCode::Q) {
-        //     colordata.position[0].z -= 0.01;
-        // } else if keyboard_input.pressed(KeyCode::E) {
-        //     colordata.position[0].z += 0.01;
-        // }
-        // println!("colordata: {}", colordata.value)
+    #[test]
+    fn uniform_layout_matches_shader() {
+        assert_uniform_matches::<CoolMaterialUniformInput, 2>("my_material_t.wgsl", 0, &[]);
     }
-    // println!("");
 }
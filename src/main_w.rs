@@ -7,18 +7,22 @@ use bevy::{
     reflect::TypeUuid,
     render::{
         camera::ScalingMode,
-        extract_resource::{ExtractResource, ExtractResourcePlugin},
-        render_resource::{
-            encase::{self},
-            AsBindGroup, OwnedBindingResource, ShaderRef, ShaderType,
-        },
-        renderer::RenderQueue,
-        Extract, RenderApp, RenderStage,
+        render_resource::{AsBindGroup, ShaderRef, ShaderType},
     },
-    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle, RenderMaterials2d},
+    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle},
     window::PresentMode,
 };
 
+mod material_binding;
+#[cfg(test)]
+mod shader_layout;
+mod uniform_bridge;
+mod wgsl_struct;
+
+use material_binding::UniformBindings;
+use uniform_bridge::UniformBridgePlugin;
+use wgsl_struct::wgsl_struct;
+
 pub const CLEAR: Color = Color::rgb(0.3, 0.3, 0.3);
 pub const HEIGHT: f32 = 600.0;
 pub const RESOLUTION: f32 = 4.0 / 3.0;
@@ -37,14 +41,10 @@ fn main() {
         })
         .add_plugins(DefaultPlugins)
         .add_plugin(Material2dPlugin::<CoolMaterial>::default())
+        .add_plugin(UniformBridgePlugin::<CoolMaterialUniformData, CoolMaterial>::default())
         .add_startup_system(spawn_camera)
         .add_system(adjust_colordata_via_kb)
-        .add_plugin(ExtractResourcePlugin::<ExtractedTime>::default())
-        .add_system(setup_shader);
-
-    app.sub_app_mut(RenderApp)
-        .add_system_to_stage(RenderStage::Extract, extract_data_to_cool_material)
-        .add_system_to_stage(RenderStage::Prepare, prepare_cool_material);
+        .add_startup_system(setup_shader);
 
     app.run();
 }
@@ -64,88 +64,14 @@ fn spawn_camera(mut commands: Commands) {
 }
 
 // This is what we will be interacing with
-#[derive(Component, ShaderType, Clone, Copy)]
-struct CoolMaterialUniformData {
-    color: Color,
-    time: f32,
-}
-
-#[derive(Clone, Default)]
-struct Fire {
-    position: Vec2,
-    size: f32,
-}
-
-#[derive(Clone, Default)]
-struct ActiveFires {
-    active_fires: Vec<Fire>,
-}
-
-impl encase::ShaderType for ActiveFires {
-    type ExtraMetadata = ();
-
-    const METADATA: encase::private::Metadata<Self::ExtraMetadata> = {
-        let size =
-            encase::private::SizeValue::from(<Vec2 as encase::private::ShaderSize>::SHADER_SIZE);
-        let alignment = encase::private::AlignmentValue::from_next_power_of_two_size(size);
-
-        encase::private::Metadata {
-            alignment,
-            has_uniform_min_alignment: false,
-            min_size: size,
-            extra: (),
-        }
-    };
-
-    const UNIFORM_COMPAT_ASSERT: fn() = || {};
-}
-
-impl encase::private::WriteInto for ActiveFires {
-    fn write_into<B: encase::private::BufferMut>(&self, writer: &mut encase::private::Writer<B>) {
-        let linear = [0];
-        for el in &linear {
-            encase::private::WriteInto::write_into(el, writer);
-        }
-    }
-}
-
-impl encase::private::ReadFrom for ActiveFires {
-    fn read_from<B: encase::private::BufferRef>(
-        &mut self,
-        reader: &mut encase::private::Reader<B>,
-    ) {
-        let mut buffer = [0.0f32; 4];
-        for el in &mut buffer {
-            encase::private::ReadFrom::read_from(el, reader);
-        }
-
-        *self = ActiveFires {
-            active_fires: vec![Fire {
-                position: Vec2::new(buffer[0], buffer[1]),
-                size: buffer[2],
-            }],
-        }
-    }
-}
-impl encase::private::CreateFrom for ActiveFires {
-    fn create_from<B>(reader: &mut encase::private::Reader<B>) -> Self
-    where
-        B: encase::private::BufferRef,
-    {
-        // These are intentionally not inlined in the constructor to make this
-        // resilient to internal Color refactors / implicit type changes.
-        let red: f32 = encase::private::CreateFrom::create_from(reader);
-        let green: f32 = encase::private::CreateFrom::create_from(reader);
-        let blue: f32 = encase::private::CreateFrom::create_from(reader);
-        let alpha: f32 = encase::private::CreateFrom::create_from(reader);
-        ActiveFires {
-            active_fires: vec![Fire {}],
-        }
+wgsl_struct! {
+    #[derive(Component, ShaderType, Clone, Copy)]
+    struct CoolMaterialUniformData {
+        color: Color,
+        time: f32,
     }
 }
 
-impl encase::ShaderSize for ActiveFires {}
-
 // Used inside the wgsl. Only initialized, but further interactions
 // are done via the CoolMaterialUniformData struct
 #[derive(AsBindGroup, TypeUuid, Clone)]
@@ -155,8 +81,6 @@ pub struct CoolMaterial {
     color: Color,
     #[uniform(0)]
     time: f32,
-    #[uniform(0)]
-    fires: ActiveFires,
     #[texture(1)]
     #[sampler(2)]
     image: Handle<Image>,
@@ -168,14 +92,15 @@ impl Default for CoolMaterial {
             color: Color::rgb(0.0, 0.0, 0.0),
             time: 0.0,
             image: Default::default(),
-            fires: ActiveFires::default(),
         }
     }
 }
 
+impl UniformBindings for CoolMaterial {}
+
 impl Material2d for CoolMaterial {
     fn fragment_shader() -> ShaderRef {
-        "my_material_2.wgsl".into()
+        "my_material_w.wgsl".into()
     }
 }
 
@@ -215,57 +140,6 @@ fn setup_shader(
     //     });
 }
 
-struct ExtractedTime {
-    seconds_since_startup: f32,
-}
-
-impl ExtractResource for ExtractedTime {
-    type Source = Time;
-
-    fn extract_resource(time: &Self::Source) -> Self {
-        ExtractedTime {
-            seconds_since_startup: time.seconds_since_startup() as f32,
-        }
-    }
-}
-
-fn extract_data_to_cool_material(
-    mut commands: Commands,
-    colordata_query: Extract<Query<(Entity, &CoolMaterialUniformData, &Handle<CoolMaterial>)>>,
-) {
-    for (entity, colordata, handle) in colordata_query.iter() {
-        commands
-            .get_or_spawn(entity)
-            .insert(*colordata)
-            .insert(handle.clone());
-    }
-}
-
-fn prepare_cool_material(
-    materials: Res<RenderMaterials2d<CoolMaterial>>,
-    colordata_query: Query<(&CoolMaterialUniformData, &Handle<CoolMaterial>)>,
-    render_queue: Res<RenderQueue>,
-    time: Res<ExtractedTime>,
-) {
-    for (colordata, handle) in &colordata_query {
-        if let Some(material) = materials.get(handle) {
-            let binding = &material.bindings[2];
-            if let OwnedBindingResource::Buffer(cur_buffer) = binding {
-                let mut buffer = encase::UniformBuffer::new(Vec::new());
-                // get color from cool material
-                buffer
-                    .write(&CoolMaterialUniformData {
-                        color: colordata.color,
-                        time: colordata.time,
-                    })
-                    .unwrap();
-
-                render_queue.write_buffer(cur_buffer, 0, buffer.as_ref());
-            }
-        }
-    }
-}
-
 fn adjust_colordata_via_kb(
     keyboard_input: Res<Input<KeyCode>>,
     mut colordata_query: Query<&mut CoolMaterialUniformData>,
@@ -280,3 +154,19 @@ fn adjust_colordata_via_kb(
     }
     // println!("");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::{assert_generated_wgsl, assert_uniform_matches};
+
+    #[test]
+    fn generated_wgsl_is_up_to_date() {
+        assert_generated_wgsl::<CoolMaterialUniformData>("generated/my_material_w.wgsl");
+    }
+
+    #[test]
+    fn uniform_layout_matches_shader() {
+        assert_uniform_matches::<CoolMaterialUniformData, 2>("my_material_w.wgsl", 0, &[]);
+    }
+}
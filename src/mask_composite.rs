@@ -0,0 +1,307 @@
+// Composite light shapes built from the SDF shapes of mask_shape.rs.
+//
+// A `ShapeTree` combines shapes with union, subtract, intersect and smooth
+// union, like "a room minus a pillar". It is compiled into a postfix program
+// that `mask_program_distance()` in mask_functions.wgsl runs on a small stack
+// of distances, and mask_sample.rs on the CPU. Every instruction takes two
+// vec4s:
+//   shape      (a, b, c, kind)   (offset.x, offset.y, cos, sin)
+//   operation  (k, 0, 0, op)     unused
+// where a shape pushes its distance, placed at offset and turned by the angle,
+// and an operation pops two distances and pushes the combined one. Programs
+// live in MAX_SHAPE_PROGRAMS shared chunks of the mask uniform, a composite
+// light points at its chunk, see `CoolMaterialUniformInput`.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::mask_shape::{LightShape, SHAPE_POLYGON};
+
+pub const MAX_SHAPE_INSTRUCTIONS: usize = 16;
+pub const SHAPE_PROGRAM_VEC4S: usize = MAX_SHAPE_INSTRUCTIONS * 2;
+// composite lights that can be active in one mask at the same time
+pub const MAX_SHAPE_PROGRAMS: usize = 8;
+// stack size of mask_program_distance()
+pub const MAX_SHAPE_STACK: usize = 8;
+
+pub type ShapeProgram = [Vec4; SHAPE_PROGRAM_VEC4S];
+
+pub const EMPTY_SHAPE_PROGRAM: ShapeProgram = [Vec4::ZERO; SHAPE_PROGRAM_VEC4S];
+
+// op codes after the shape kinds, mask_program_distance() matches on the same numbers
+pub const OP_UNION: u32 = 8;
+pub const OP_SUBTRACT: u32 = 9;
+pub const OP_INTERSECT: u32 = 10;
+pub const OP_SMOOTH_UNION: u32 = 11;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ShapeTree {
+    // circles have a diameter of 1, polygons, cones and composites can't be leaves
+    Shape {
+        shape: LightShape,
+        #[serde(default)]
+        offset: Vec2,
+        // radians
+        #[serde(default)]
+        rotation: f32,
+    },
+    Union(Box<ShapeTree>, Box<ShapeTree>),
+    // the first minus the second
+    Subtract(Box<ShapeTree>, Box<ShapeTree>),
+    Intersect(Box<ShapeTree>, Box<ShapeTree>),
+    // blends the outlines together over about k
+    SmoothUnion(Box<ShapeTree>, Box<ShapeTree>, f32),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ShapeTreeError {
+    TooManyInstructions,
+    StackOverflow,
+    UnsupportedLeaf,
+    // the shader divides by k
+    NoSmoothing(f32),
+}
+
+impl std::fmt::Display for ShapeTreeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ShapeTreeError::TooManyInstructions => write!(
+                f,
+                "shape tree needs more than {} instructions",
+                MAX_SHAPE_INSTRUCTIONS
+            ),
+            ShapeTreeError::StackOverflow => write!(
+                f,
+                "shape tree is nested deeper than {} distances",
+                MAX_SHAPE_STACK
+            ),
+            ShapeTreeError::UnsupportedLeaf => {
+                write!(
+                    f,
+                    "polygons, cones and composites can't be part of a shape tree"
+                )
+            }
+            ShapeTreeError::NoSmoothing(k) => {
+                write!(f, "smooth union needs a k above 0, got {}", k)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ShapeTreeError {}
+
+impl ShapeTree {
+    pub fn shape(shape: LightShape) -> Self {
+        ShapeTree::Shape {
+            shape,
+            offset: Vec2::ZERO,
+            rotation: 0.0,
+        }
+    }
+
+    // Moves a leaf, does nothing to operations
+    pub fn at(mut self, to: Vec2) -> Self {
+        if let ShapeTree::Shape { offset, .. } = &mut self {
+            *offset = to;
+        }
+        self
+    }
+
+    // Turns a leaf, does nothing to operations
+    pub fn rotated(mut self, angle: f32) -> Self {
+        if let ShapeTree::Shape { rotation, .. } = &mut self {
+            *rotation = angle;
+        }
+        self
+    }
+
+    pub fn union(self, other: ShapeTree) -> Self {
+        ShapeTree::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn subtract(self, other: ShapeTree) -> Self {
+        ShapeTree::Subtract(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersect(self, other: ShapeTree) -> Self {
+        ShapeTree::Intersect(Box::new(self), Box::new(other))
+    }
+
+    pub fn smooth_union(self, other: ShapeTree, k: f32) -> Self {
+        ShapeTree::SmoothUnion(Box::new(self), Box::new(other), k)
+    }
+
+    // The program and its number of instructions
+    pub fn compile(&self) -> Result<(ShapeProgram, usize), ShapeTreeError> {
+        let mut program = EMPTY_SHAPE_PROGRAM;
+        let mut len = 0;
+        self.emit(&mut program, &mut len, 0)?;
+        Ok((program, len))
+    }
+
+    // Postfix order, `depth` is the number of distances already on the stack
+    fn emit(
+        &self,
+        program: &mut ShapeProgram,
+        len: &mut usize,
+        depth: usize,
+    ) -> Result<(), ShapeTreeError> {
+        if depth >= MAX_SHAPE_STACK {
+            return Err(ShapeTreeError::StackOverflow);
+        }
+        if let ShapeTree::SmoothUnion(_, _, k) = self {
+            if k.is_nan() || *k <= 0.0 {
+                return Err(ShapeTreeError::NoSmoothing(*k));
+            }
+        }
+        let (a, b) = match self {
+            ShapeTree::Shape {
+                shape,
+                offset,
+                rotation,
+            } => {
+                let packed = match shape {
+                    LightShape::Polygon { .. }
+                    | LightShape::Composite(_)
+                    | LightShape::Cone { .. } => return Err(ShapeTreeError::UnsupportedLeaf),
+                    shape => shape.try_pack()?.shape,
+                };
+                (
+                    packed,
+                    Vec4::new(offset.x, offset.y, rotation.cos(), rotation.sin()),
+                )
+            }
+            ShapeTree::Union(first, second)
+            | ShapeTree::Subtract(first, second)
+            | ShapeTree::Intersect(first, second)
+            | ShapeTree::SmoothUnion(first, second, _) => {
+                first.emit(program, len, depth)?;
+                second.emit(program, len, depth + 1)?;
+                let (op, k) = match self {
+                    ShapeTree::Union(..) => (OP_UNION, 0.0),
+                    ShapeTree::Subtract(..) => (OP_SUBTRACT, 0.0),
+                    ShapeTree::Intersect(..) => (OP_INTERSECT, 0.0),
+                    ShapeTree::SmoothUnion(_, _, k) => (OP_SMOOTH_UNION, *k),
+                    ShapeTree::Shape { .. } => unreachable!(),
+                };
+                (Vec4::new(k, 0.0, 0.0, op as f32), Vec4::ZERO)
+            }
+        };
+        if *len >= MAX_SHAPE_INSTRUCTIONS {
+            return Err(ShapeTreeError::TooManyInstructions);
+        }
+        program[*len * 2] = a;
+        program[*len * 2 + 1] = b;
+        *len += 1;
+        Ok(())
+    }
+
+    // Reverse of `compile`, None for a program `compile` can't have written
+    pub fn decompile(program: &ShapeProgram, len: usize) -> Option<Self> {
+        let mut stack = Vec::new();
+        for instruction in program.chunks(2).take(len) {
+            let (a, b) = (instruction[0], instruction[1]);
+            let op = a.w as u32;
+            if op < SHAPE_POLYGON {
+                stack.push(ShapeTree::Shape {
+                    shape: LightShape::unpack_sdf(a),
+                    offset: Vec2::new(b.x, b.y),
+                    rotation: b.w.atan2(b.z),
+                });
+                continue;
+            }
+            let second = Box::new(stack.pop()?);
+            let first = Box::new(stack.pop()?);
+            stack.push(match op {
+                OP_UNION => ShapeTree::Union(first, second),
+                OP_SUBTRACT => ShapeTree::Subtract(first, second),
+                OP_INTERSECT => ShapeTree::Intersect(first, second),
+                OP_SMOOTH_UNION => ShapeTree::SmoothUnion(first, second, a.x),
+                _ => return None,
+            });
+        }
+        match (stack.pop(), stack.is_empty()) {
+            (Some(tree), true) => Some(tree),
+            _ => None,
+        }
+    }
+
+    // Radius around the local origin that holds the whole tree
+    pub fn extent(&self) -> f32 {
+        match self {
+            ShapeTree::Shape { shape, offset, .. } => offset.length() + shape.extent(),
+            ShapeTree::Union(first, second) => first.extent().max(second.extent()),
+            ShapeTree::Subtract(first, _) => first.extent(),
+            ShapeTree::Intersect(first, second) => first.extent().min(second.extent()),
+            // the blend grows the outline by at most k / 4
+            ShapeTree::SmoothUnion(first, second, k) => {
+                first.extent().max(second.extent()) + k * 0.25
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room_minus_pillar() -> ShapeTree {
+        ShapeTree::shape(LightShape::RoundedBox {
+            half_size: Vec2::new(0.3, 0.2),
+            corner_radius: 0.0,
+        })
+        .subtract(
+            ShapeTree::shape(LightShape::Ellipse {
+                radii: Vec2::splat(0.05),
+            })
+            .at(Vec2::new(0.1, 0.0)),
+        )
+    }
+
+    #[test]
+    fn compile_round_trips() {
+        let tree = room_minus_pillar().smooth_union(
+            ShapeTree::shape(LightShape::Capsule {
+                half_length: 0.1,
+                radius: 0.05,
+            })
+            .rotated(0.5),
+            0.05,
+        );
+        let (program, len) = tree.compile().unwrap();
+        assert_eq!(len, 5);
+        // the angle goes through cos and sin, so compare the programs
+        let decompiled = ShapeTree::decompile(&program, len).unwrap();
+        let (recompiled, recompiled_len) = decompiled.compile().unwrap();
+        assert_eq!(recompiled_len, len);
+        for (a, b) in program.iter().zip(recompiled.iter()) {
+            assert!((*a - *b).abs().max_element() < 1e-6, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn compile_rejects_what_the_shader_cant_run() {
+        let leaf = || ShapeTree::shape(LightShape::Circle);
+        let mut deep = leaf();
+        for _ in 0..MAX_SHAPE_STACK {
+            deep = leaf().union(deep);
+        }
+        assert_eq!(deep.compile(), Err(ShapeTreeError::StackOverflow));
+
+        let mut long = leaf();
+        for _ in 0..MAX_SHAPE_INSTRUCTIONS {
+            long = long.union(leaf());
+        }
+        assert_eq!(long.compile(), Err(ShapeTreeError::TooManyInstructions));
+
+        let polygon = ShapeTree::shape(LightShape::Polygon {
+            vertices: vec![Vec2::ZERO, Vec2::X, Vec2::Y],
+        });
+        assert_eq!(polygon.compile(), Err(ShapeTreeError::UnsupportedLeaf));
+
+        let sharp = leaf().smooth_union(leaf(), 0.0);
+        assert_eq!(sharp.compile(), Err(ShapeTreeError::NoSmoothing(0.0)));
+        let negative = leaf().smooth_union(leaf(), -0.1);
+        assert_eq!(negative.compile(), Err(ShapeTreeError::NoSmoothing(-0.1)));
+    }
+}
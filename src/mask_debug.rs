@@ -0,0 +1,387 @@
+// Debug overlay for the light mask, toggled with F1.
+//
+// Draws every light's edge and softness band, a cross at its center and the
+// overlay quad's bounds as line meshes. Lights that did not fit in the
+// MAX_LIGHTS slots are drawn in red. The meshes are only rewritten when a mask
+// changed, not every frame.
+
+use bevy::{
+    prelude::*,
+    render::mesh::PrimitiveTopology,
+    sprite::{MaterialMesh2dBundle, Mesh2dHandle},
+};
+
+use crate::{
+    mask_post_process::ScreenMask,
+    mask_shape::{
+        polygon_vertex, SHAPE_CAPSULE, SHAPE_CIRCLE, SHAPE_COMPOSITE, SHAPE_CONE, SHAPE_ELLIPSE,
+        SHAPE_POLYGON, SHAPE_RING, SHAPE_ROUNDED_BOX,
+    },
+    CoolMaterialUniformInput, DroppedLights, LightSlot, OVERLAY_SIZE,
+};
+
+pub const MASK_DEBUG_TOGGLE: KeyCode = KeyCode::F1;
+
+const CIRCLE_SEGMENTS: usize = 32;
+const CROSS_SIZE: f32 = 0.02;
+// Above the overlay so the lines are never hidden by it
+const DEBUG_Z: f32 = 10.0;
+
+pub struct MaskDebugPlugin;
+
+impl Plugin for MaskDebugPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MaskDebugSettings>()
+            .add_startup_system(spawn_mask_debug_lines)
+            .add_system(toggle_mask_debug)
+            .add_system(draw_mask_debug);
+    }
+}
+
+#[derive(Default)]
+pub struct MaskDebugSettings {
+    pub enabled: bool,
+}
+
+#[derive(Component, Clone, Copy, PartialEq, Eq)]
+enum MaskDebugLines {
+    Lights,
+    Dropped,
+    Overlay,
+}
+
+impl MaskDebugLines {
+    fn color(self) -> Color {
+        match self {
+            MaskDebugLines::Lights => Color::rgb(0.0, 1.0, 0.3),
+            MaskDebugLines::Dropped => Color::rgb(1.0, 0.1, 0.1),
+            MaskDebugLines::Overlay => Color::rgb(0.2, 0.5, 1.0),
+        }
+    }
+}
+
+fn spawn_mask_debug_lines(
+    mut commands: Commands,
+    mut mesh_assets: ResMut<Assets<Mesh>>,
+    mut color_materials: ResMut<Assets<ColorMaterial>>,
+) {
+    for lines in [
+        MaskDebugLines::Lights,
+        MaskDebugLines::Dropped,
+        MaskDebugLines::Overlay,
+    ] {
+        commands
+            .spawn_bundle(MaterialMesh2dBundle {
+                mesh: mesh_assets
+                    .add(Mesh::new(PrimitiveTopology::LineList))
+                    .into(),
+                material: color_materials.add(ColorMaterial::from(lines.color())),
+                transform: Transform::from_xyz(0.0, 0.0, DEBUG_Z),
+                visibility: Visibility { is_visible: false },
+                ..default()
+            })
+            .insert(lines);
+    }
+}
+
+fn toggle_mask_debug(keyboard_input: Res<Input<KeyCode>>, mut settings: ResMut<MaskDebugSettings>) {
+    if keyboard_input.just_pressed(MASK_DEBUG_TOGGLE) {
+        settings.enabled = !settings.enabled;
+    }
+}
+
+fn draw_mask_debug(
+    settings: Res<MaskDebugSettings>,
+    overlay_query: Query<(
+        &CoolMaterialUniformInput,
+        &GlobalTransform,
+        Option<&DroppedLights>,
+        Option<&ScreenMask>,
+    )>,
+    changed_query: Query<
+        (),
+        (
+            With<CoolMaterialUniformInput>,
+            Or<(
+                Changed<CoolMaterialUniformInput>,
+                Changed<GlobalTransform>,
+                Changed<DroppedLights>,
+            )>,
+        ),
+    >,
+    removed: RemovedComponents<CoolMaterialUniformInput>,
+    mut lines_query: Query<(&MaskDebugLines, &Mesh2dHandle, &mut Visibility)>,
+    mut mesh_assets: ResMut<Assets<Mesh>>,
+) {
+    if !settings.enabled {
+        if settings.is_changed() {
+            for (_, _, mut visibility) in lines_query.iter_mut() {
+                visibility.is_visible = false;
+            }
+        }
+        return;
+    }
+    // the lines of the last redraw are still right
+    if !settings.is_changed() && changed_query.is_empty() && removed.iter().next().is_none() {
+        return;
+    }
+
+    let mut lights = Vec::new();
+    let mut dropped_lights = Vec::new();
+    let mut overlay = Vec::new();
+    for (colordata, transform, dropped, screen_mask) in overlay_query.iter() {
+        for light in colordata.lights() {
+            push_light(&mut lights, &light);
+        }
+        for light in dropped.into_iter().flat_map(|d| d.lights.iter()) {
+            push_light(&mut dropped_lights, light);
+        }
+        // a screen mask has no bounds to draw
+        if screen_mask.is_none() {
+            push_overlay_bounds(&mut overlay, transform);
+        }
+    }
+
+    for (lines, mesh_handle, mut visibility) in lines_query.iter_mut() {
+        let positions = match lines {
+            MaskDebugLines::Lights => std::mem::take(&mut lights),
+            MaskDebugLines::Dropped => std::mem::take(&mut dropped_lights),
+            MaskDebugLines::Overlay => std::mem::take(&mut overlay),
+        };
+        visibility.is_visible = !positions.is_empty();
+        if let Some(mesh) = mesh_assets.get_mut(&mesh_handle.0) {
+            set_line_positions(mesh, positions);
+        }
+    }
+}
+
+// The shader compares 4 * dist² against radius, so the visible edge of a light
+// is at sqrt(radius) / 2 and the softness band scales radius by (1 ± softness)
+fn light_extent(radius: f32, softness: f32) -> (f32, f32, f32) {
+    let to_world = |r: f32| r.max(0.0).sqrt() * 0.5;
+    (
+        to_world(radius - radius * softness),
+        to_world(radius),
+        to_world(radius + radius * softness),
+    )
+}
+
+// Every light is drawn as its edge and the two outlines of its softness band,
+// cones only as their edge and composites as the circle around their extent.
+// The cross at the center turns to the light's local axes
+fn push_light(lines: &mut Vec<[f32; 3]>, light: &LightSlot) {
+    let position = light.position;
+    let center = position.truncate().truncate();
+    let (mut x_axis, mut y_axis) = (Vec2::X, Vec2::Y);
+    if light.shape.w as u32 == SHAPE_CIRCLE {
+        let (inner, edge, outer) = light_extent(position.z, position.w);
+        for radius in [inner, edge, outer] {
+            if radius > 0.0 {
+                push_circle(lines, center, radius);
+            }
+        }
+    } else {
+        let orientation = light.orientation;
+        // the rows of the world to local matrix
+        let to_world = Mat2::from_cols(
+            Vec2::new(orientation.x, orientation.z),
+            Vec2::new(orientation.y, orientation.w),
+        )
+        .inverse();
+        match light.shape.w as u32 {
+            SHAPE_CONE => push_cone(lines, center, to_world, light.shape.x, light.shape.y),
+            SHAPE_COMPOSITE => push_circle(lines, center, position.z),
+            _ => {
+                // softness is the half width of the band in local units
+                for offset in [-position.w, 0.0, position.w] {
+                    for outline in shape_outlines(light, offset) {
+                        push_loop(lines, outline.into_iter().map(|p| center + to_world * p));
+                    }
+                }
+            }
+        }
+        x_axis = to_world.x_axis.normalize_or_zero();
+        y_axis = to_world.y_axis.normalize_or_zero();
+    }
+    push_segment(
+        lines,
+        center - x_axis * CROSS_SIZE,
+        center + x_axis * CROSS_SIZE,
+    );
+    push_segment(
+        lines,
+        center - y_axis * CROSS_SIZE,
+        center + y_axis * CROSS_SIZE,
+    );
+}
+
+// Closed outlines in local space where the distance of a light's shape is
+// `offset`. Ellipses are grown along their radii, which is close enough for
+// a debug view
+fn shape_outlines(light: &LightSlot, offset: f32) -> Vec<Vec<Vec2>> {
+    let shape = light.shape;
+    match shape.w as u32 {
+        SHAPE_ROUNDED_BOX => {
+            let radius = (shape.z + offset).max(0.0);
+            let corner = Vec2::new(shape.x, shape.y) + offset - radius;
+            if corner.min_element() + radius <= 0.0 {
+                return Vec::new();
+            }
+            let corners = [
+                corner,
+                Vec2::new(-corner.x, corner.y),
+                -corner,
+                Vec2::new(corner.x, -corner.y),
+            ];
+            let outline = corners
+                .iter()
+                .enumerate()
+                .flat_map(|(i, corner)| {
+                    let start = i as f32 * std::f32::consts::FRAC_PI_2;
+                    arc(*corner, radius, start, std::f32::consts::FRAC_PI_2)
+                })
+                .collect();
+            vec![outline]
+        }
+        SHAPE_ELLIPSE => {
+            let radii = Vec2::new(shape.x, shape.y) + offset;
+            if radii.min_element() <= 0.0 {
+                return Vec::new();
+            }
+            vec![circle_points().map(|p| p * radii).collect()]
+        }
+        SHAPE_CAPSULE => {
+            let radius = shape.y + offset;
+            if radius <= 0.0 {
+                return Vec::new();
+            }
+            let half_length = Vec2::new(shape.x, 0.0);
+            let half_turn = std::f32::consts::PI;
+            let mut outline = arc(half_length, radius, -half_turn * 0.5, half_turn);
+            outline.extend(arc(-half_length, radius, half_turn * 0.5, half_turn));
+            vec![outline]
+        }
+        SHAPE_RING => {
+            let half_width = shape.y * 0.5 + offset;
+            if half_width <= 0.0 {
+                return Vec::new();
+            }
+            [shape.x - half_width, shape.x + half_width]
+                .into_iter()
+                .filter(|radius| *radius > 0.0)
+                .map(|radius| circle_points().map(|p| p * radius).collect())
+                .collect()
+        }
+        SHAPE_POLYGON => {
+            let vertices: Vec<Vec2> = (0..shape.x as usize)
+                .map(|i| polygon_vertex(&light.polygon, i))
+                .collect();
+            vec![polygon_outline(&vertices, offset)]
+        }
+        _ => Vec::new(),
+    }
+}
+
+// A convex polygon moved out by `offset` along its edge normals: rounded
+// around the corners when grown, mitered when shrunk
+fn polygon_outline(vertices: &[Vec2], offset: f32) -> Vec<Vec2> {
+    let count = vertices.len();
+    let area: f32 = (0..count)
+        .map(|i| vertices[i].perp_dot(vertices[(i + 1) % count]))
+        .sum();
+    // outward for either winding
+    let normal = |i: usize| {
+        let edge = vertices[(i + 1) % count] - vertices[i];
+        Vec2::new(edge.y, -edge.x).normalize_or_zero() * area.signum()
+    };
+    let mut outline = Vec::new();
+    for i in 0..count {
+        let (before, after) = (normal((i + count - 1) % count), normal(i));
+        if offset > 0.0 {
+            let start = before.y.atan2(before.x);
+            let turn = before.perp_dot(after).atan2(before.dot(after));
+            outline.extend(arc(vertices[i], offset, start, turn));
+        } else {
+            let miter = (before + after) / (1.0 + before.dot(after)).max(1e-3);
+            outline.push(vertices[i] + miter * offset);
+        }
+    }
+    outline
+}
+
+// Points along an arc from `start`, `angle` radians counterclockwise
+fn arc(center: Vec2, radius: f32, start: f32, angle: f32) -> Vec<Vec2> {
+    let segments =
+        ((angle.abs() / std::f32::consts::TAU * CIRCLE_SEGMENTS as f32).ceil() as usize).max(1);
+    (0..=segments)
+        .map(|i| {
+            let angle = start + angle * i as f32 / segments as f32;
+            center + Vec2::new(angle.cos(), angle.sin()) * radius
+        })
+        .collect()
+}
+
+fn circle_points() -> impl Iterator<Item = Vec2> {
+    (0..CIRCLE_SEGMENTS).map(|i| {
+        let angle = i as f32 / CIRCLE_SEGMENTS as f32 * std::f32::consts::TAU;
+        Vec2::new(angle.cos(), angle.sin())
+    })
+}
+
+fn push_loop(lines: &mut Vec<[f32; 3]>, points: impl Iterator<Item = Vec2>) {
+    let points: Vec<Vec2> = points.collect();
+    for (i, point) in points.iter().enumerate() {
+        push_segment(lines, *point, points[(i + 1) % points.len()]);
+    }
+}
+
+fn push_circle(lines: &mut Vec<[f32; 3]>, center: Vec2, radius: f32) {
+    let point = |i: usize| {
+        let angle = i as f32 / CIRCLE_SEGMENTS as f32 * std::f32::consts::TAU;
+        center + Vec2::new(angle.cos(), angle.sin()) * radius
+    };
+    for i in 0..CIRCLE_SEGMENTS {
+        push_segment(lines, point(i), point(i + 1));
+    }
+}
+
+fn push_cone(lines: &mut Vec<[f32; 3]>, center: Vec2, to_world: Mat2, half_angle: f32, range: f32) {
+    let point = |i: usize| {
+        let angle = -half_angle + 2.0 * half_angle * i as f32 / CIRCLE_SEGMENTS as f32;
+        center + to_world * (Vec2::new(angle.cos(), angle.sin()) * range)
+    };
+    push_segment(lines, center, point(0));
+    push_segment(lines, center, point(CIRCLE_SEGMENTS));
+    for i in 0..CIRCLE_SEGMENTS {
+        push_segment(lines, point(i), point(i + 1));
+    }
+}
+
+fn push_overlay_bounds(lines: &mut Vec<[f32; 3]>, transform: &GlobalTransform) {
+    let matrix = transform.compute_matrix();
+    let half = OVERLAY_SIZE * 0.5;
+    let corners = [
+        Vec2::new(-half.x, -half.y),
+        Vec2::new(half.x, -half.y),
+        Vec2::new(half.x, half.y),
+        Vec2::new(-half.x, half.y),
+    ]
+    .map(|corner| matrix.transform_point3(corner.extend(0.0)).truncate());
+    for i in 0..corners.len() {
+        push_segment(lines, corners[i], corners[(i + 1) % corners.len()]);
+    }
+}
+
+fn push_segment(lines: &mut Vec<[f32; 3]>, start: Vec2, end: Vec2) {
+    lines.push([start.x, start.y, 0.0]);
+    lines.push([end.x, end.y, 0.0]);
+}
+
+// Replaces the lines of a line list mesh. The 2d mesh pipeline always expects
+// normals and uvs, even for lines
+fn set_line_positions(mesh: &mut Mesh, positions: Vec<[f32; 3]>) {
+    let vertex_count = positions.len();
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 0.0, 1.0]; vertex_count]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0]; vertex_count]);
+}
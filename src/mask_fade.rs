@@ -0,0 +1,436 @@
+// Fading lights in and out.
+//
+// A light with a `LightFade` is scaled by the eased progress of the fade, so
+// its hole opens from a point instead of popping in, and closes the same way.
+// Shapes shrink around their origin, beams around their middle. On a
+// `LightSource` or `LightBeam` entity, a finished fade in removes the
+// component and a finished fade out despawns the entity with its children.
+// Lights pushed into the mask by hand have no entity, they are faded with
+// `CoolMaterialUniformInput::fade_slot` and keep the fade in the mask: a
+// finished fade out removes the light from its slot. Either way a `LightFaded`
+// is sent.
+
+use bevy::prelude::*;
+
+use crate::{CoolMaterialUniformInput, LightSlot};
+
+pub struct LightFadePlugin;
+
+impl Plugin for LightFadePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<LightFaded>()
+            .add_system(advance_light_fades)
+            .add_system(advance_slot_fades);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Easing {
+    Linear,
+    // slow start
+    EaseIn,
+    // slow end
+    EaseOut,
+    #[default]
+    EaseInOut,
+    // any curve from 0 at 0 to 1 at 1
+    Custom(fn(f32) -> f32),
+}
+
+impl Easing {
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => t * (2.0 - t),
+            Easing::EaseInOut if t < 0.5 => 2.0 * t * t,
+            Easing::EaseInOut => 1.0 - 2.0 * (1.0 - t) * (1.0 - t),
+            Easing::Custom(curve) => curve(t),
+        }
+    }
+}
+
+// Custom curves compare by address, so two copies of one function can differ.
+// That only costs the mask an upload
+impl PartialEq for Easing {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Easing::Linear, Easing::Linear)
+            | (Easing::EaseIn, Easing::EaseIn)
+            | (Easing::EaseOut, Easing::EaseOut)
+            | (Easing::EaseInOut, Easing::EaseInOut) => true,
+            (Easing::Custom(curve), Easing::Custom(other)) => *curve as usize == *other as usize,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FadeDirection {
+    In,
+    // despawns the light when done
+    Out,
+}
+
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct LightFade {
+    pub direction: FadeDirection,
+    // seconds
+    pub duration: f32,
+    pub easing: Easing,
+    pub elapsed: f32,
+}
+
+impl LightFade {
+    pub fn fade_in(duration: f32, easing: Easing) -> Self {
+        Self {
+            direction: FadeDirection::In,
+            duration,
+            easing,
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn fade_out(duration: f32, easing: Easing) -> Self {
+        Self {
+            direction: FadeDirection::Out,
+            duration,
+            easing,
+            elapsed: 0.0,
+        }
+    }
+
+    // The opposite fade, starting from the current size, like fading out a
+    // light that is still fading in
+    pub fn reversed(&self) -> Self {
+        let factor = self.factor();
+        let mut reversed = Self {
+            direction: match self.direction {
+                FadeDirection::In => FadeDirection::Out,
+                FadeDirection::Out => FadeDirection::In,
+            },
+            ..*self
+        };
+        // the point of the new curve with the same size, found by bisection
+        // as custom curves can't be inverted
+        let (mut low, mut high) = (0.0, 1.0);
+        for _ in 0..24 {
+            reversed.elapsed = (low + high) * 0.5 * self.duration;
+            let too_far = match reversed.direction {
+                FadeDirection::In => reversed.factor() > factor,
+                FadeDirection::Out => reversed.factor() < factor,
+            };
+            if too_far {
+                high = (low + high) * 0.5;
+            } else {
+                low = (low + high) * 0.5;
+            }
+        }
+        reversed
+    }
+
+    pub fn progress(&self) -> f32 {
+        if self.duration <= 0.0 {
+            return 1.0;
+        }
+        (self.elapsed / self.duration).min(1.0)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.progress() >= 1.0
+    }
+
+    // How much of its full size the light has, 0 to 1
+    pub fn factor(&self) -> f32 {
+        let eased = self.easing.apply(self.progress());
+        match self.direction {
+            FadeDirection::In => eased,
+            FadeDirection::Out => 1.0 - eased,
+        }
+    }
+}
+
+// The fade of a light pushed by hand, with the light at its full size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlotFade {
+    pub light: LightSlot,
+    pub fade: LightFade,
+}
+
+pub struct LightFaded {
+    // the source or beam, or the mask of a light pushed by hand
+    pub entity: Entity,
+    // the slot a light pushed by hand was in
+    pub slot: Option<usize>,
+    pub direction: FadeDirection,
+}
+
+fn advance_light_fades(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut fade_query: Query<(Entity, &mut LightFade)>,
+    mut faded_events: EventWriter<LightFaded>,
+) {
+    for (entity, mut fade) in fade_query.iter_mut() {
+        fade.elapsed += time.delta_seconds();
+        if !fade.is_finished() {
+            continue;
+        }
+        match fade.direction {
+            FadeDirection::In => {
+                commands.entity(entity).remove::<LightFade>();
+            }
+            FadeDirection::Out => commands.entity(entity).despawn_recursive(),
+        }
+        faded_events.send(LightFaded {
+            entity,
+            slot: None,
+            direction: fade.direction,
+        });
+    }
+}
+
+fn advance_slot_fades(
+    time: Res<Time>,
+    mut mask_query: Query<(Entity, &mut CoolMaterialUniformInput)>,
+    mut faded_events: EventWriter<LightFaded>,
+) {
+    for (mask, mut input) in mask_query.iter_mut() {
+        // only masks with a fade are touched, the rest are not uploaded again
+        if input.fades.iter().all(Option::is_none) {
+            continue;
+        }
+        // back to front, so removing a light doesn't move the ones still to come
+        for slot in (0..input.own_light_count()).rev() {
+            let mut slot_fade = match input.fades[slot] {
+                Some(slot_fade) => slot_fade,
+                None => continue,
+            };
+            slot_fade.fade.elapsed += time.delta_seconds();
+            if !slot_fade.fade.is_finished() {
+                input.fade_slot(slot, slot_fade.fade);
+                continue;
+            }
+            match slot_fade.fade.direction {
+                FadeDirection::In => {
+                    input.fades[slot] = None;
+                    input.set_slot(slot, &slot_fade.light);
+                }
+                FadeDirection::Out => input.remove_light(slot),
+            }
+            faded_events.send(LightFaded {
+                entity: mask,
+                slot: Some(slot),
+                direction: slot_fade.fade.direction,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mask_shape::LightShape,
+        mask_source::{LightBeam, LightSource, LightSourcePlugin},
+    };
+    use bevy::utils::{Duration, Instant};
+
+    #[test]
+    fn easings_start_at_0_and_end_at_1() {
+        for easing in [
+            Easing::Linear,
+            Easing::EaseIn,
+            Easing::EaseOut,
+            Easing::EaseInOut,
+            Easing::Custom(|t| t * t * t),
+        ] {
+            assert_eq!(easing.apply(0.0), 0.0);
+            assert_eq!(easing.apply(1.0), 1.0);
+            let mut last = 0.0;
+            for i in 1..=10 {
+                let eased = easing.apply(i as f32 / 10.0);
+                assert!(eased >= last, "{:?} goes back at {}", easing, i);
+                last = eased;
+            }
+        }
+    }
+
+    #[test]
+    fn reversed_fades_keep_the_size() {
+        let mut fade = LightFade::fade_in(1.0, Easing::EaseIn);
+        fade.elapsed = 0.3;
+        let reversed = fade.reversed();
+        assert_eq!(reversed.direction, FadeDirection::Out);
+        assert!((reversed.factor() - fade.factor()).abs() < 1e-4);
+    }
+
+    fn app() -> App {
+        let mut app = App::default();
+        app.add_plugin(TransformPlugin)
+            .add_plugin(LightSourcePlugin)
+            .add_plugin(LightFadePlugin)
+            .init_resource::<Time>();
+        app.world
+            .resource_mut::<Time>()
+            .update_with_instant(Instant::now());
+        app
+    }
+
+    fn step(app: &mut App, seconds: f32) {
+        let now = app.world.resource::<Time>().last_update().unwrap();
+        app.world
+            .resource_mut::<Time>()
+            .update_with_instant(now + Duration::from_secs_f32(seconds));
+        app.update();
+    }
+
+    fn faded(app: &App) -> Vec<(Entity, Option<usize>, FadeDirection)> {
+        let events = app.world.resource::<Events<LightFaded>>();
+        events
+            .get_reader()
+            .iter(events)
+            .map(|event| (event.entity, event.slot, event.direction))
+            .collect()
+    }
+
+    fn spawn_at(app: &mut App, translation: Vec3) -> Entity {
+        app.world
+            .spawn()
+            .insert_bundle(TransformBundle::from_transform(
+                Transform::from_translation(translation),
+            ))
+            .id()
+    }
+
+    #[test]
+    fn faded_out_sources_shrink_then_despawn() {
+        let mut app = app();
+        let mask = app
+            .world
+            .spawn()
+            .insert(CoolMaterialUniformInput::default())
+            .id();
+        let source = app
+            .world
+            .spawn()
+            .insert_bundle(TransformBundle::from_transform(Transform::from_scale(
+                Vec3::splat(0.2),
+            )))
+            .insert(LightSource::new(mask, LightShape::Circle))
+            .insert(LightFade::fade_out(0.5, Easing::Linear))
+            .id();
+        let from = spawn_at(&mut app, Vec3::new(-0.2, 0.0, 0.0));
+        let to = spawn_at(&mut app, Vec3::new(0.2, 0.0, 0.0));
+        let beam = LightBeam::new(mask, from, to, 0.05);
+        let beam_entity = app
+            .world
+            .spawn()
+            .insert(beam.clone())
+            .insert(LightFade::fade_out(0.5, Easing::Linear))
+            .id();
+
+        step(&mut app, 0.25);
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        let lights: Vec<LightSlot> = input.source_lights().collect();
+        // half the diameter, the shader gets it squared
+        assert!((lights[0].position.z - 0.1 * 0.1).abs() < 1e-4);
+        // the whole capsule shrinks, softness included
+        let (start, end) = (Vec2::new(-0.2, 0.0), Vec2::new(0.2, 0.0));
+        let full = beam.slot(start, end);
+        assert_eq!(lights[1], beam.faded_slot(start, end, 0.5));
+        assert!((lights[1].position.z - full.position.z * 0.5).abs() < 1e-6);
+        assert!(
+            (lights[1].orientation - full.orientation * 2.0)
+                .abs()
+                .max_element()
+                < 1e-4
+        );
+
+        step(&mut app, 0.3);
+        assert!(app.world.get_entity(source).is_none());
+        assert!(app.world.get_entity(beam_entity).is_none());
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        assert_eq!(input.light_count, 0);
+        let faded = faded(&app);
+        assert_eq!(faded.len(), 2);
+        assert!(faded.contains(&(source, None, FadeDirection::Out)));
+        assert!(faded.contains(&(beam_entity, None, FadeDirection::Out)));
+    }
+
+    #[test]
+    fn broken_beams_fade_out_where_they_were() {
+        let mut app = app();
+        let mask = app
+            .world
+            .spawn()
+            .insert(CoolMaterialUniformInput::default())
+            .id();
+        let from = spawn_at(&mut app, Vec3::new(-0.2, 0.0, 0.0));
+        let to = spawn_at(&mut app, Vec3::new(0.2, 0.1, 0.0));
+        let mut beam = LightBeam::new(mask, from, to, 0.05);
+        beam.break_fade = 0.5;
+        let beam_entity = app.world.spawn().insert(beam.clone()).id();
+        step(&mut app, 0.1);
+
+        app.world.despawn(to);
+        step(&mut app, 0.1);
+        let fade = *app.world.get::<LightFade>(beam_entity).unwrap();
+        assert_eq!(fade.direction, FadeDirection::Out);
+        let (start, end) = (Vec2::new(-0.2, 0.0), Vec2::new(0.2, 0.1));
+        step(&mut app, 0.25);
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        let factor = app.world.get::<LightFade>(beam_entity).unwrap().factor();
+        assert!(factor > 0.0 && factor < 1.0);
+        assert_eq!(
+            input.source_lights().collect::<Vec<_>>(),
+            vec![beam.faded_slot(start, end, factor)]
+        );
+
+        step(&mut app, 0.3);
+        assert!(app.world.get_entity(beam_entity).is_none());
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        assert_eq!(input.light_count, 0);
+        assert_eq!(faded(&app), vec![(beam_entity, None, FadeDirection::Out)]);
+    }
+
+    #[test]
+    fn pushed_lights_fade_in_their_slot() {
+        let mut app = app();
+        let mut input = CoolMaterialUniformInput::default();
+        let light = |x: f32| LightSlot::circle(Vec4::new(x, 0.0, 0.04, 0.5), Color::WHITE);
+        assert!(input.push_slot(&light(0.1)));
+        assert!(input.push_faded_in(&light(0.2), LightFade::fade_in(0.5, Easing::Linear)));
+        assert!(input.push_slot(&light(0.3)));
+        // grows from a point
+        assert_eq!(input.slot(1).position.z, 0.0);
+        let mask = app.world.spawn().insert(input).id();
+
+        step(&mut app, 0.25);
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        assert!((input.slot(1).position.z - 0.04 * 0.25).abs() < 1e-6);
+        // fading lights are saved at their full size
+        assert_eq!(input.own_lights().nth(1), Some(light(0.2)));
+
+        // the fade follows its light when the one before it goes
+        let mut input = app.world.get_mut::<CoolMaterialUniformInput>(mask).unwrap();
+        input.remove_light(0);
+        input.fade_slot(1, LightFade::fade_out(0.5, Easing::Linear));
+        step(&mut app, 0.3);
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        assert_eq!(input.slot(0), light(0.2));
+        assert!(input.slot_fade(0).is_none());
+        assert!((input.slot(1).position.z - 0.04 * 0.4 * 0.4).abs() < 1e-6);
+        assert_eq!(faded(&app), vec![(mask, Some(0), FadeDirection::In)]);
+
+        step(&mut app, 0.3);
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        assert_eq!(input.lights().collect::<Vec<_>>(), vec![light(0.2)]);
+        // the fade in of the last frame is still in the events
+        assert_eq!(
+            faded(&app).last(),
+            Some(&(mask, Some(1), FadeDirection::Out))
+        );
+    }
+}
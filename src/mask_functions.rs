@@ -0,0 +1,26 @@
+// Keeps mask_functions.wgsl loaded so shaders can `#import mask::functions`.
+//
+// The pipeline cache resolves a custom import path only against shaders that
+// are loaded, and nothing else asks for this file, so the plugin holds a
+// handle to it for the whole run. Loading it through the asset server keeps
+// hot reloading working for the shared math too.
+
+use bevy::prelude::*;
+
+pub struct MaskFunctionsPlugin;
+
+impl Plugin for MaskFunctionsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MaskFunctionsShader>();
+    }
+}
+
+// Only held, never read
+#[allow(dead_code)]
+struct MaskFunctionsShader(Handle<Shader>);
+
+impl FromWorld for MaskFunctionsShader {
+    fn from_world(world: &mut World) -> Self {
+        Self(world.resource::<AssetServer>().load("mask_functions.wgsl"))
+    }
+}
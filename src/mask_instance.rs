@@ -0,0 +1,175 @@
+// Per-entity mask data for overlays that share one `Handle<CoolMaterial>`.
+//
+// The lights of an overlay are written into its material's uniform buffer, so
+// two entities with the same handle would both show whichever entity was
+// prepared last. Every extra user of a shared handle gets its own copy of the
+// material instead, which gives it its own buffer and bind group. The copies
+// keep following the shader settings of the material they were made from.
+
+use bevy::{asset::HandleId, prelude::*, utils::HashSet};
+
+use crate::{CoolMaterial, CoolMaterialUniformInput};
+
+pub struct MaskInstancePlugin;
+
+impl Plugin for MaskInstancePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(CoreStage::PostUpdate, instance_shared_mask_materials)
+            .add_system(sync_mask_material_instances);
+    }
+}
+
+// The material an overlay's own copy was made from
+#[derive(Component)]
+pub struct MaskMaterialInstance {
+    pub template: Handle<CoolMaterial>,
+}
+
+fn instance_shared_mask_materials(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<CoolMaterial>>,
+    mut overlay_query: Query<
+        (Entity, &mut Handle<CoolMaterial>),
+        (
+            With<CoolMaterialUniformInput>,
+            Without<MaskMaterialInstance>,
+        ),
+    >,
+    mut claimed: Local<HashSet<HandleId>>,
+) {
+    claimed.clear();
+    for (entity, mut handle) in overlay_query.iter_mut() {
+        // the first user keeps the shared handle
+        if claimed.insert(handle.id) {
+            continue;
+        }
+        let instance = match materials.get(&handle) {
+            Some(material) => material.clone(),
+            None => continue,
+        };
+        let template = std::mem::replace(&mut *handle, materials.add(instance));
+        commands
+            .entity(entity)
+            .insert(MaskMaterialInstance { template });
+    }
+}
+
+fn sync_mask_material_instances(
+    mut material_events: EventReader<AssetEvent<CoolMaterial>>,
+    mut materials: ResMut<Assets<CoolMaterial>>,
+    instance_query: Query<(&Handle<CoolMaterial>, &MaskMaterialInstance)>,
+) {
+    for event in material_events.iter() {
+        let changed = match event {
+            AssetEvent::Modified { handle } => handle,
+            _ => continue,
+        };
+        let template = match materials.get(changed) {
+            Some(template) => template.clone(),
+            None => continue,
+        };
+        for (handle, instance) in instance_query.iter() {
+            if instance.template != *changed {
+                continue;
+            }
+            if let Some(material) = materials.get_mut(handle) {
+                material.falloff = template.falloff;
+                material.blend = template.blend;
+                material.tint = template.tint;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MaskFalloff;
+    use bevy::asset::AssetPlugin;
+
+    fn app() -> App {
+        let mut app = App::default();
+        app.add_plugin(AssetPlugin)
+            .add_asset::<CoolMaterial>()
+            .add_plugin(MaskInstancePlugin);
+        app
+    }
+
+    fn spawn_overlay(app: &mut App, handle: &Handle<CoolMaterial>) -> Entity {
+        app.world
+            .spawn()
+            .insert(CoolMaterialUniformInput::default())
+            .insert(handle.clone())
+            .id()
+    }
+
+    fn handle(app: &App, overlay: Entity) -> Handle<CoolMaterial> {
+        app.world
+            .get::<Handle<CoolMaterial>>(overlay)
+            .unwrap()
+            .clone()
+    }
+
+    // Spawns two overlays on one material, the second of the pair returned
+    // is the one that got the copy
+    fn shared_pair(app: &mut App, shared: &Handle<CoolMaterial>) -> (Entity, Entity) {
+        let first = spawn_overlay(app, shared);
+        let second = spawn_overlay(app, shared);
+        app.update();
+        if handle(app, first) == *shared {
+            (first, second)
+        } else {
+            (second, first)
+        }
+    }
+
+    #[test]
+    fn overlays_sharing_a_material_get_their_own() {
+        let mut app = app();
+        let shared = app
+            .world
+            .resource_mut::<Assets<CoolMaterial>>()
+            .add(CoolMaterial::default());
+        let (owner, copy) = shared_pair(&mut app, &shared);
+
+        let copy_handle = handle(&app, copy);
+        assert_eq!(handle(&app, owner), shared);
+        assert_ne!(copy_handle, shared);
+        assert!(app
+            .world
+            .resource::<Assets<CoolMaterial>>()
+            .get(&copy_handle)
+            .is_some());
+        let instance = app.world.get::<MaskMaterialInstance>(copy).unwrap();
+        assert_eq!(instance.template, shared);
+        assert!(app.world.get::<MaskMaterialInstance>(owner).is_none());
+
+        // the copy is made once, not every frame
+        app.update();
+        assert_eq!(handle(&app, owner), shared);
+        assert_eq!(handle(&app, copy), copy_handle);
+    }
+
+    #[test]
+    fn copies_follow_their_template() {
+        let mut app = app();
+        let shared = app
+            .world
+            .resource_mut::<Assets<CoolMaterial>>()
+            .add(CoolMaterial::default());
+        let (_, copy) = shared_pair(&mut app, &shared);
+        let copy_handle = handle(&app, copy);
+
+        let mut materials = app.world.resource_mut::<Assets<CoolMaterial>>();
+        let template = materials.get_mut(&shared).unwrap();
+        template.falloff = MaskFalloff::Hard;
+        template.tint = false;
+        // the event goes out at the end of this frame and is read in the next
+        app.update();
+        app.update();
+        let materials = app.world.resource::<Assets<CoolMaterial>>();
+        let instance = materials.get(&copy_handle).unwrap();
+        assert_eq!(instance.falloff, MaskFalloff::Hard);
+        assert!(!instance.tint);
+    }
+}
@@ -0,0 +1,303 @@
+// The light mask as a full-screen pass instead of an overlay quad.
+//
+// A camera with `MaskPostProcess` gets the mask of the `mask` entity drawn over
+// everything its main 2d pass rendered. The mask entity carries the same
+// `CoolMaterialUniformInput` and `Handle<CoolMaterial>` an overlay quad would,
+// so uploads, scenes and the keyboard controls work unchanged, but it has no
+// mesh and covers the whole view. The pass reuses `fragment()` of
+// my_material_t_2.wgsl and the material's bind group; mask_post_process.wgsl
+// only adds a vertex shader that feeds it world positions.
+
+use bevy::{
+    core_pipeline::core_2d,
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, SlotInfo, SlotType},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice},
+        texture::BevyDefault,
+        view::{ExtractedView, ViewTarget},
+        RenderApp, RenderStage,
+    },
+    sprite::{Material2dPipeline, RenderMaterials2d},
+};
+
+use crate::{CoolMaterial, CoolMaterialKey};
+
+pub const MASK_POST_PROCESS: &str = "mask_post_process";
+
+pub struct MaskPostProcessPlugin;
+
+impl Plugin for MaskPostProcessPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(ExtractComponentPlugin::<MaskPostProcess>::default());
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<MaskPostProcessPipeline>()
+            .init_resource::<SpecializedRenderPipelines<MaskPostProcessPipeline>>()
+            .add_system_to_stage(RenderStage::Queue, queue_mask_post_process);
+
+        let node = MaskPostProcessNode::new(&mut render_app.world);
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        let core_2d_graph = graph.get_sub_graph_mut(core_2d::graph::NAME).unwrap();
+        core_2d_graph.add_node(MASK_POST_PROCESS, node);
+        let input_node_id = core_2d_graph.input_node().unwrap().id;
+        core_2d_graph
+            .add_slot_edge(
+                input_node_id,
+                core_2d::graph::input::VIEW_ENTITY,
+                MASK_POST_PROCESS,
+                MaskPostProcessNode::IN_VIEW,
+            )
+            .unwrap();
+        core_2d_graph
+            .add_node_edge(core_2d::graph::node::MAIN_PASS, MASK_POST_PROCESS)
+            .unwrap();
+    }
+}
+
+// On a camera, draws the mask of `mask` over the camera's output
+#[derive(Component, Clone, Copy)]
+pub struct MaskPostProcess {
+    pub mask: Entity,
+}
+
+impl ExtractComponent for MaskPostProcess {
+    type Query = &'static MaskPostProcess;
+    type Filter = ();
+
+    fn extract_component(item: QueryItem<Self::Query>) -> Self {
+        *item
+    }
+}
+
+// Marks a mask entity that is drawn by `MaskPostProcess` instead of a quad,
+// it masks everything the camera sees rather than the bounds of a mesh
+#[derive(Component, Clone, Copy, Default)]
+pub struct ScreenMask;
+
+#[derive(Clone, ShaderType)]
+struct MaskPostProcessUniform {
+    inverse_view_proj: Mat4,
+}
+
+#[derive(Component)]
+struct MaskPostProcessView {
+    pipeline: CachedRenderPipelineId,
+    bind_group: BindGroup,
+}
+
+fn queue_mask_post_process(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    post_process_pipeline: Res<MaskPostProcessPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<MaskPostProcessPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    msaa: Res<Msaa>,
+    materials: Res<RenderMaterials2d<CoolMaterial>>,
+    mask_query: Query<&Handle<CoolMaterial>>,
+    views: Query<(Entity, &ExtractedView, &MaskPostProcess)>,
+) {
+    for (entity, view, post_process) in views.iter() {
+        let material = match mask_query
+            .get(post_process.mask)
+            .ok()
+            .and_then(|handle| materials.get(handle))
+        {
+            Some(material) => material,
+            None => continue,
+        };
+        let pipeline = pipelines.specialize(
+            &mut pipeline_cache,
+            &post_process_pipeline,
+            MaskPostProcessKey {
+                material: material.key,
+                samples: msaa.samples,
+            },
+        );
+
+        let mut uniform = encase::UniformBuffer::new(Vec::new());
+        uniform
+            .write(&MaskPostProcessUniform {
+                inverse_view_proj: view.transform.compute_matrix() * view.projection.inverse(),
+            })
+            .unwrap();
+        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("mask_post_process_uniform_buffer"),
+            contents: uniform.as_ref(),
+            usage: BufferUsages::UNIFORM,
+        });
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("mask_post_process_bind_group"),
+            layout: &post_process_pipeline.view_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        });
+        commands.entity(entity).insert(MaskPostProcessView {
+            pipeline,
+            bind_group,
+        });
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MaskPostProcessKey {
+    material: CoolMaterialKey,
+    samples: u32,
+}
+
+pub struct MaskPostProcessPipeline {
+    vertex_shader: Handle<Shader>,
+    fragment_shader: Handle<Shader>,
+    view_layout: BindGroupLayout,
+    material_layout: BindGroupLayout,
+}
+
+impl FromWorld for MaskPostProcessPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let asset_server = world.resource::<AssetServer>();
+        let vertex_shader = asset_server.load("mask_post_process.wgsl");
+        let fragment_shader = asset_server.load("my_material_t_2.wgsl");
+        let render_device = world.resource::<RenderDevice>();
+        let view_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("mask_post_process_view_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(MaskPostProcessUniform::min_size()),
+                },
+                count: None,
+            }],
+        });
+
+        Self {
+            vertex_shader,
+            fragment_shader,
+            view_layout,
+            // the material's bind group is bound as is, so its layout has to match
+            material_layout: world
+                .resource::<Material2dPipeline<CoolMaterial>>()
+                .material2d_layout
+                .clone(),
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for MaskPostProcessPipeline {
+    type Key = MaskPostProcessKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
+            label: Some("mask_post_process_pipeline".into()),
+            layout: Some(vec![self.view_layout.clone(), self.material_layout.clone()]),
+            vertex: VertexState {
+                shader: self.vertex_shader.clone(),
+                shader_defs: Vec::new(),
+                entry_point: "vertex".into(),
+                buffers: Vec::new(),
+            },
+            fragment: Some(FragmentState {
+                shader: self.fragment_shader.clone(),
+                shader_defs: key.material.shader_defs(),
+                entry_point: "fragment".into(),
+                // blended like the overlay quad, so both look the same
+                targets: vec![Some(ColorTargetState {
+                    format: TextureFormat::bevy_default(),
+                    blend: Some(BlendState::ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState {
+                count: key.samples,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        }
+    }
+}
+
+struct MaskPostProcessNode {
+    view_query: QueryState<(
+        &'static ViewTarget,
+        &'static MaskPostProcess,
+        &'static MaskPostProcessView,
+    )>,
+}
+
+impl MaskPostProcessNode {
+    const IN_VIEW: &'static str = "view";
+
+    fn new(world: &mut World) -> Self {
+        Self {
+            view_query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for MaskPostProcessNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.view_query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        // cameras without a mask skip the pass
+        let (target, post_process, post_process_view) =
+            match self.view_query.get_manual(world, view_entity) {
+                Ok(view) => view,
+                Err(_) => return Ok(()),
+            };
+        let material = match world
+            .get::<Handle<CoolMaterial>>(post_process.mask)
+            .and_then(|handle| {
+                world
+                    .resource::<RenderMaterials2d<CoolMaterial>>()
+                    .get(handle)
+            }) {
+            Some(material) => material,
+            None => return Ok(()),
+        };
+        let pipeline = match world
+            .resource::<PipelineCache>()
+            .get_render_pipeline(post_process_view.pipeline)
+        {
+            Some(pipeline) => pipeline,
+            None => return Ok(()),
+        };
+
+        let mut pass = render_context
+            .command_encoder
+            .begin_render_pass(&RenderPassDescriptor {
+                label: Some("mask_post_process_pass"),
+                color_attachments: &[Some(target.get_color_attachment(Operations {
+                    load: LoadOp::Load,
+                    store: true,
+                }))],
+                depth_stencil_attachment: None,
+            });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &post_process_view.bind_group, &[]);
+        pass.set_bind_group(1, &material.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+        Ok(())
+    }
+}
@@ -0,0 +1,600 @@
+// CPU mirror of `fragment()` in my_material_t_2.wgsl.
+//
+// Gameplay code asks "is this point lit?" and has to get the same answer the
+// player sees, so everything here follows the shader line by line. Any change
+// to mask_functions.wgsl or the combine loop in the shader has to be made here
+// too, the tests below pin the numbers both sides are expected to produce.
+
+use bevy::prelude::*;
+
+use crate::{
+    mask_composite::{
+        ShapeProgram, MAX_SHAPE_STACK, OP_INTERSECT, OP_SMOOTH_UNION, OP_SUBTRACT, OP_UNION,
+    },
+    mask_shape::{
+        polygon_vertex, POLYGON_VEC4S, SHAPE_CAPSULE, SHAPE_CIRCLE, SHAPE_CONE, SHAPE_ELLIPSE,
+        SHAPE_RING, SHAPE_ROUNDED_BOX,
+    },
+    CoolMaterialKey, CoolMaterialUniformInput, LightSlot, MaskBlend, MaskFalloff, OVERLAY_SIZE,
+};
+
+// WGSL smoothstep. WGSL leaves edge0 >= edge1 undefined and Rust would give
+// NaN, so `falloff()` never gets here without a soft band
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// WGSL step
+fn step(edge: f32, x: f32) -> f32 {
+    if x >= edge {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+// `mask_falloff()` from mask_functions.wgsl: 0 inside the light, 1 outside
+fn falloff(falloff: MaskFalloff, value: f32, radius: f32, softness: f32) -> f32 {
+    // no soft band, both soft falloffs turn into a hard edge
+    if radius * softness <= 0.0 {
+        return step(radius, value);
+    }
+    match falloff {
+        MaskFalloff::Smooth => smoothstep(
+            radius - (radius * softness),
+            radius + (radius * softness),
+            value,
+        ),
+        MaskFalloff::Linear => {
+            ((value - (radius - (radius * softness))) / (2.0 * radius * softness)).clamp(0.0, 1.0)
+        }
+        MaskFalloff::Hard => step(radius, value),
+    }
+}
+
+// `mask_light()`
+fn circle(kind: MaskFalloff, st: Vec2, center: Vec2, radius: f32, smoothness: f32) -> f32 {
+    let dist = st - center;
+    falloff(kind, dist.dot(dist) * 4.0, radius, smoothness)
+}
+
+// The signed distances of mask_functions.wgsl, negative inside
+fn rounded_box(p: Vec2, half_size: Vec2, corner_radius: f32) -> f32 {
+    let q = p.abs() - half_size + corner_radius;
+    q.max(Vec2::ZERO).length() + q.x.max(q.y).min(0.0) - corner_radius
+}
+
+fn ellipse(p: Vec2, radii: Vec2) -> f32 {
+    let k0 = (p / radii).length();
+    if k0 == 0.0 {
+        return -radii.min_element();
+    }
+    let k1 = (p / (radii * radii)).length();
+    k0 * (k0 - 1.0) / k1
+}
+
+fn capsule(p: Vec2, half_length: f32, radius: f32) -> f32 {
+    Vec2::new(p.x - p.x.clamp(-half_length, half_length), p.y).length() - radius
+}
+
+fn ring(p: Vec2, radius: f32, thickness: f32) -> f32 {
+    (p.length() - radius).abs() - thickness * 0.5
+}
+
+fn polygon(p: Vec2, vertices: &[Vec4; POLYGON_VEC4S], count: usize) -> f32 {
+    let first = polygon_vertex(vertices, 0);
+    let mut d = (p - first).dot(p - first);
+    let mut s = 1.0;
+    let mut j = count.wrapping_sub(1);
+    for i in 0..count {
+        let vi = polygon_vertex(vertices, i);
+        let vj = polygon_vertex(vertices, j);
+        let e = vj - vi;
+        let w = p - vi;
+        let b = w - e * (w.dot(e) / e.dot(e)).clamp(0.0, 1.0);
+        d = d.min(b.dot(b));
+        let c = [p.y >= vi.y, p.y < vj.y, e.x * w.y > e.y * w.x];
+        if c.iter().all(|c| *c) || !c.iter().any(|c| *c) {
+            s = -s;
+        }
+        j = i;
+    }
+    s * d.sqrt()
+}
+
+// `mask_shape_distance()`
+fn shape_distance(p: Vec2, shape: Vec4, vertices: &[Vec4; POLYGON_VEC4S]) -> f32 {
+    match shape.w as u32 {
+        SHAPE_CIRCLE => p.length() - 0.5,
+        SHAPE_ROUNDED_BOX => rounded_box(p, Vec2::new(shape.x, shape.y), shape.z),
+        SHAPE_ELLIPSE => ellipse(p, Vec2::new(shape.x, shape.y)),
+        SHAPE_CAPSULE => capsule(p, shape.x, shape.y),
+        SHAPE_RING => ring(p, shape.x, shape.y),
+        _ => polygon(p, vertices, shape.x as usize),
+    }
+}
+
+fn smooth_union(a: f32, b: f32, k: f32) -> f32 {
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+// `mask_cone_light()`
+fn cone(kind: MaskFalloff, p: Vec2, shape: Vec4, softness: f32) -> f32 {
+    let radial = falloff(kind, p.length() - shape.y + 1.0, 1.0, softness);
+    let angular = falloff(kind, p.y.atan2(p.x).abs() - shape.x + 1.0, 1.0, shape.z);
+    1.0 - (1.0 - radial) * (1.0 - angular)
+}
+
+// `mask_program_distance()`
+fn program_distance(p: Vec2, program: &ShapeProgram, len: usize) -> f32 {
+    if len == 0 {
+        return 1.0e9;
+    }
+    let mut stack = [0.0; MAX_SHAPE_STACK];
+    let mut top = 0;
+    for instruction in program.chunks(2).take(len) {
+        let (a, b) = (instruction[0], instruction[1]);
+        let op = a.w as u32;
+        if op < OP_UNION {
+            let dist = p - Vec2::new(b.x, b.y);
+            let local = Vec2::new(b.z * dist.x + b.w * dist.y, b.z * dist.y - b.w * dist.x);
+            stack[top] = shape_distance(local, a, &[Vec4::ZERO; POLYGON_VEC4S]);
+            top += 1;
+        } else {
+            let (first, second) = (stack[top - 2], stack[top - 1]);
+            top -= 1;
+            stack[top - 1] = match op {
+                OP_SUBTRACT => first.max(-second),
+                OP_INTERSECT => first.max(second),
+                OP_SMOOTH_UNION => smooth_union(first, second, a.x),
+                _ => first.min(second),
+            };
+        }
+    }
+    stack[0]
+}
+
+// `mask_slot_light()` and `mask_composite_light()`
+fn slot_light(kind: MaskFalloff, st: Vec2, light: &LightSlot) -> f32 {
+    let position = light.position;
+    if light.shape.w as u32 == SHAPE_CIRCLE {
+        return circle(
+            kind,
+            st,
+            position.truncate().truncate(),
+            position.z,
+            position.w,
+        );
+    }
+    let dist = st - position.truncate().truncate();
+    let orientation = light.orientation;
+    let local = Vec2::new(
+        orientation.x * dist.x + orientation.y * dist.y,
+        orientation.z * dist.x + orientation.w * dist.y,
+    );
+    if light.shape.w as u32 == SHAPE_CONE {
+        return cone(kind, local, light.shape, position.w);
+    }
+    let distance = if light.is_composite() {
+        program_distance(local, &light.program, light.shape.x as usize)
+    } else {
+        shape_distance(local, light.shape, &light.polygon)
+    };
+    falloff(kind, distance + 1.0, 1.0, position.w)
+}
+
+// `mask_slot_light()` for every active light, with the light's color
+fn light_coverages<'a>(
+    input: &'a CoolMaterialUniformInput,
+    key: &'a CoolMaterialKey,
+    world_pos: Vec2,
+) -> impl Iterator<Item = (f32, Vec4)> + 'a {
+    input.lights().map(move |light| {
+        let coverage = slot_light(key.falloff, world_pos, &light);
+        (coverage, Vec4::from(light.color.as_linear_rgba_f32()))
+    })
+}
+
+// The color the shader writes for a fragment at `world_pos`. Colors reach the
+// uniform in linear space, so the result is linear as well
+pub fn sample_mask_color(
+    input: &CoolMaterialUniformInput,
+    key: &CoolMaterialKey,
+    world_pos: Vec2,
+) -> Vec4 {
+    let mut output_color = Vec4::from(input.color.as_linear_rgba_f32());
+    match key.blend {
+        MaskBlend::Multiply => {
+            for (coverage, light_color) in light_coverages(input, key, world_pos) {
+                output_color *= coverage;
+                if key.tint {
+                    output_color += light_color * (1.0 - coverage);
+                }
+            }
+        }
+        MaskBlend::Min => {
+            let mut darkness = 1.0;
+            let mut tint = Vec4::ZERO;
+            for (coverage, light_color) in light_coverages(input, key, world_pos) {
+                if coverage < darkness {
+                    darkness = coverage;
+                    tint = light_color;
+                }
+            }
+            output_color *= darkness;
+            if key.tint {
+                output_color += tint * (1.0 - darkness);
+            }
+        }
+    }
+    output_color
+}
+
+// How much light reaches `world_pos`, 0 is fully masked and 1 fully lit.
+// Points outside the overlay quad are never masked.
+pub fn sample_mask(
+    input: &CoolMaterialUniformInput,
+    key: &CoolMaterialKey,
+    overlay_transform: &GlobalTransform,
+    world_pos: Vec2,
+) -> f32 {
+    if !in_overlay(overlay_transform, world_pos) {
+        return 1.0;
+    }
+    sample_lights(input, key, world_pos)
+}
+
+// Whether the overlay quad placed by `overlay_transform` covers `world_pos`
+pub fn in_overlay(overlay_transform: &GlobalTransform, world_pos: Vec2) -> bool {
+    let local = overlay_transform
+        .compute_matrix()
+        .inverse()
+        .transform_point3(world_pos.extend(0.0))
+        .truncate();
+    let half = OVERLAY_SIZE * 0.5;
+    local.x.abs() <= half.x && local.y.abs() <= half.y
+}
+
+// How much of one light reaches `world_pos`, the others ignored
+pub fn sample_light(light: &LightSlot, key: &CoolMaterialKey, world_pos: Vec2) -> f32 {
+    1.0 - slot_light(key.falloff, world_pos, light)
+}
+
+// `sample_mask` without the overlay bounds, for masks that cover the whole view
+pub fn sample_lights(
+    input: &CoolMaterialUniformInput,
+    key: &CoolMaterialKey,
+    world_pos: Vec2,
+) -> f32 {
+    let coverages = light_coverages(input, key, world_pos).map(|(coverage, _)| coverage);
+    let darkness = match key.blend {
+        MaskBlend::Multiply => coverages.product::<f32>(),
+        MaskBlend::Min => coverages.fold(1.0, f32::min),
+    };
+    (1.0 - darkness).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mask_composite::ShapeTree, mask_shape::LightShape};
+
+    fn overlay(lights: &[Vec4]) -> CoolMaterialUniformInput {
+        let mut input = CoolMaterialUniformInput {
+            color: Color::rgba(0.0, 0.0, 0.0, 0.75),
+            ..Default::default()
+        };
+        for light in lights {
+            input.push_light(*light, Color::rgba(0.0, 0.0, 0.0, 0.0));
+        }
+        input
+    }
+
+    fn key() -> CoolMaterialKey {
+        CoolMaterialKey::default()
+    }
+
+    fn identity() -> GlobalTransform {
+        GlobalTransform::from(Transform::default())
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn circle_reference_values() {
+        // radius 0.04, softness 1.0: smoothstep(0.0, 0.08, 4 * dist²)
+        let light = Vec4::new(0.0, 0.0, 0.04, 1.0);
+        let input = overlay(&[light]);
+        assert_close(sample_mask(&input, &key(), &identity(), Vec2::ZERO), 1.0);
+        assert_close(
+            sample_mask(&input, &key(), &identity(), Vec2::new(0.1, 0.0)),
+            0.5,
+        );
+        assert_close(
+            sample_mask(&input, &key(), &identity(), Vec2::new(0.2, 0.0)),
+            0.0,
+        );
+    }
+
+    #[test]
+    fn softness_narrows_the_band() {
+        // radius 0.04, softness 0.5: smoothstep(0.02, 0.06, 0.05) = 0.84375
+        let light = Vec4::new(0.0, 0.0, 0.04, 0.5);
+        let input = overlay(&[light]);
+        assert_close(
+            sample_mask(&input, &key(), &identity(), Vec2::new(0.1, 0.05)),
+            1.0 - 0.84375,
+        );
+        assert_close(
+            sample_mask(&input, &key(), &identity(), Vec2::new(0.05, 0.0)),
+            1.0,
+        );
+    }
+
+    #[test]
+    fn zero_softness_is_a_hard_edge() {
+        let input = overlay(&[Vec4::new(0.0, 0.0, 0.04, 0.0)]);
+        assert_close(
+            sample_mask(&input, &key(), &identity(), Vec2::new(0.09, 0.0)),
+            1.0,
+        );
+        assert_close(
+            sample_mask(&input, &key(), &identity(), Vec2::new(0.11, 0.0)),
+            0.0,
+        );
+    }
+
+    #[test]
+    fn lights_multiply() {
+        let input = overlay(&[
+            Vec4::new(-0.1, 0.0, 0.04, 1.0),
+            Vec4::new(0.1, 0.0, 0.04, 1.0),
+        ]);
+        // 0.1 away from both centers, each light lets half through
+        assert_close(sample_mask(&input, &key(), &identity(), Vec2::ZERO), 0.75);
+        assert_close(sample_mask_color(&input, &key(), Vec2::ZERO).w, 0.75 * 0.25);
+    }
+
+    #[test]
+    fn light_color_tints_the_hole() {
+        let mut input = overlay(&[Vec4::new(0.0, 0.0, 0.04, 1.0)]);
+        input.light_color[0] = Color::rgba_linear(1.0, 0.5, 0.0, 0.2);
+        let color = sample_mask_color(&input, &key(), Vec2::new(0.1, 0.0));
+        assert_close(color.x, 0.5);
+        assert_close(color.y, 0.25);
+        assert_close(color.z, 0.0);
+        assert_close(color.w, 0.75 * 0.5 + 0.2 * 0.5);
+    }
+
+    #[test]
+    fn outside_the_overlay_is_lit() {
+        let input = overlay(&[]);
+        assert_close(sample_mask(&input, &key(), &identity(), Vec2::ZERO), 0.0);
+        assert_close(
+            sample_mask(&input, &key(), &identity(), Vec2::new(0.6, 0.0)),
+            1.0,
+        );
+    }
+
+    #[test]
+    fn falloff_variants() {
+        // radius 0.04, softness 1.0, 4 * dist² = 0.02 at (0.05, 0.05)
+        let input = overlay(&[Vec4::new(0.0, 0.0, 0.04, 1.0)]);
+        let at = Vec2::new(0.05, 0.05);
+        let linear = CoolMaterialKey {
+            falloff: MaskFalloff::Linear,
+            ..key()
+        };
+        assert_close(sample_mask(&input, &linear, &identity(), at), 1.0 - 0.25);
+        let hard = CoolMaterialKey {
+            falloff: MaskFalloff::Hard,
+            ..key()
+        };
+        assert_close(sample_mask(&input, &hard, &identity(), at), 1.0);
+        assert_close(
+            sample_mask(&input, &hard, &identity(), Vec2::new(0.1, 0.0)),
+            0.0,
+        );
+    }
+
+    #[test]
+    fn linear_falloff_without_a_band_is_a_hard_edge() {
+        let linear = CoolMaterialKey {
+            falloff: MaskFalloff::Linear,
+            ..key()
+        };
+        let input = overlay(&[Vec4::new(0.0, 0.0, 0.04, 0.0)]);
+        assert_close(
+            sample_mask(&input, &linear, &identity(), Vec2::new(0.09, 0.0)),
+            1.0,
+        );
+        assert_close(
+            sample_mask(&input, &linear, &identity(), Vec2::new(0.11, 0.0)),
+            0.0,
+        );
+        // a light of radius 0 covers nothing, away from its center or on it
+        let input = overlay(&[Vec4::new(0.0, 0.0, 0.0, 1.0)]);
+        for at in [Vec2::ZERO, Vec2::new(0.1, 0.0)] {
+            let lit = sample_mask(&input, &linear, &identity(), at);
+            assert!(lit.is_finite());
+            assert_close(lit, 0.0);
+        }
+    }
+
+    #[test]
+    fn min_blend_keeps_the_strongest_light() {
+        let input = overlay(&[
+            Vec4::new(-0.1, 0.0, 0.04, 1.0),
+            Vec4::new(0.1, 0.0, 0.04, 1.0),
+        ]);
+        let min = CoolMaterialKey {
+            blend: MaskBlend::Min,
+            ..key()
+        };
+        assert_close(sample_mask(&input, &min, &identity(), Vec2::ZERO), 0.5);
+        assert_close(sample_mask_color(&input, &min, Vec2::ZERO).w, 0.75 * 0.5);
+    }
+
+    // One shaped light with a soft band of 0.02, placed by `transform`
+    fn shaped(shape: LightShape, transform: Transform) -> CoolMaterialUniformInput {
+        let mut input = overlay(&[]);
+        let color = Color::rgba(0.0, 0.0, 0.0, 0.0);
+        assert!(input.push_slot(&shape.to_slot(transform.compute_matrix(), 0.02, color)));
+        input
+    }
+
+    fn lit(input: &CoolMaterialUniformInput, at: Vec2) -> f32 {
+        sample_lights(input, &key(), at)
+    }
+
+    #[test]
+    fn shapes_are_half_lit_on_their_outline() {
+        for (shape, inside, outline, outside) in [
+            (
+                LightShape::RoundedBox {
+                    half_size: Vec2::new(0.1, 0.05),
+                    corner_radius: 0.01,
+                },
+                Vec2::ZERO,
+                Vec2::new(0.1, 0.0),
+                Vec2::new(0.0, 0.1),
+            ),
+            (
+                LightShape::Ellipse {
+                    radii: Vec2::new(0.2, 0.1),
+                },
+                Vec2::new(0.15, 0.0),
+                Vec2::new(0.2, 0.0),
+                Vec2::new(0.0, 0.15),
+            ),
+            (
+                LightShape::Capsule {
+                    half_length: 0.1,
+                    radius: 0.05,
+                },
+                Vec2::new(0.1, 0.0),
+                Vec2::new(0.15, 0.0),
+                Vec2::new(0.0, 0.1),
+            ),
+            (
+                LightShape::Ring {
+                    radius: 0.1,
+                    thickness: 0.04,
+                },
+                Vec2::new(0.0, 0.1),
+                Vec2::new(0.12, 0.0),
+                Vec2::ZERO,
+            ),
+            (
+                LightShape::Polygon {
+                    vertices: vec![
+                        Vec2::new(0.0, 0.08),
+                        Vec2::new(-0.07, -0.05),
+                        Vec2::new(0.07, -0.05),
+                    ],
+                },
+                Vec2::ZERO,
+                Vec2::new(0.0, -0.05),
+                Vec2::new(0.0, 0.2),
+            ),
+        ] {
+            let input = shaped(shape.clone(), Transform::default());
+            assert_close(lit(&input, inside), 1.0);
+            assert_close(lit(&input, outline), 0.5);
+            assert_close(lit(&input, outside), 0.0);
+        }
+    }
+
+    #[test]
+    fn shapes_follow_rotation_and_scale() {
+        let shape = LightShape::RoundedBox {
+            half_size: Vec2::new(0.1, 0.05),
+            corner_radius: 0.0,
+        };
+        let turned = shaped(
+            shape.clone(),
+            Transform::from_xyz(0.2, 0.0, 0.0)
+                .with_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2)),
+        );
+        assert_close(lit(&turned, Vec2::new(0.2, 0.1)), 0.5);
+        assert_close(lit(&turned, Vec2::new(0.3, 0.0)), 0.0);
+
+        let scaled = shaped(shape, Transform::from_scale(Vec3::new(2.0, 1.0, 1.0)));
+        assert_close(lit(&scaled, Vec2::new(0.2, 0.0)), 0.5);
+        assert_close(lit(&scaled, Vec2::new(0.15, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn composites_combine_their_shapes() {
+        let pillar = || {
+            ShapeTree::shape(LightShape::Ellipse {
+                radii: Vec2::splat(0.05),
+            })
+            .at(Vec2::new(0.1, 0.0))
+        };
+        let room = || {
+            ShapeTree::shape(LightShape::RoundedBox {
+                half_size: Vec2::new(0.3, 0.2),
+                corner_radius: 0.0,
+            })
+        };
+        let composite = |tree| shaped(LightShape::Composite(tree), Transform::default());
+
+        let subtracted = composite(room().subtract(pillar()));
+        assert_close(lit(&subtracted, Vec2::new(-0.1, 0.0)), 1.0);
+        assert_close(lit(&subtracted, Vec2::new(0.1, 0.0)), 0.0);
+        assert_close(lit(&subtracted, Vec2::new(0.15, 0.0)), 0.5);
+
+        let intersected = composite(room().intersect(pillar()));
+        assert_close(lit(&intersected, Vec2::new(0.1, 0.0)), 1.0);
+        assert_close(lit(&intersected, Vec2::new(-0.1, 0.0)), 0.0);
+
+        // two circles 0.2 apart at their closest, the blend pulls the outline
+        // out to the middle between them
+        let circle = |x| {
+            ShapeTree::shape(LightShape::Ellipse {
+                radii: Vec2::splat(0.2),
+            })
+            .at(Vec2::new(x, 0.0))
+        };
+        let union = composite(circle(-0.3).union(circle(0.3)));
+        assert_close(lit(&union, Vec2::new(0.3, 0.0)), 1.0);
+        assert_close(lit(&union, Vec2::ZERO), 0.0);
+        let smooth = composite(circle(-0.3).smooth_union(circle(0.3), 0.4));
+        assert_close(lit(&smooth, Vec2::ZERO), 0.5);
+    }
+
+    #[test]
+    fn cones_fade_at_their_end_and_sides() {
+        // 90 degrees wide, turned to look up
+        let cone = LightShape::Cone {
+            angle: std::f32::consts::FRAC_PI_2,
+            range: 0.4,
+            angular_softness: 0.1,
+        };
+        let light = cone.to_slot(
+            Transform::from_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2))
+                .compute_matrix(),
+            0.02,
+            Color::WHITE,
+        );
+        let at = |x: f32, y: f32| sample_light(&light, &key(), Vec2::new(x, y));
+        assert_close(at(0.0, 0.2), 1.0);
+        assert_close(at(0.0, 0.4), 0.5);
+        assert_close(at(0.0, 0.5), 0.0);
+        assert_close(at(0.0, -0.2), 0.0);
+        // on a side, then where a side meets the end
+        assert_close(at(0.1, 0.1), 0.5);
+        let corner = 0.4 * std::f32::consts::FRAC_1_SQRT_2;
+        assert_close(at(corner, corner), 0.25);
+    }
+}
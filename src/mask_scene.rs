@@ -0,0 +1,436 @@
+// Save / load of the whole mask state (overlay + every light) so a tuned
+// lighting setup can be committed next to a level instead of being lost on exit.
+//
+// The on-disk format is picked from the file extension: `.ron` or `.json`.
+// `.mask.ron` files are also a Bevy asset, so with the asset watcher on an
+// edited file is pushed into the running overlay.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{mask_shape::LightShape, CoolMaterialUniformInput, DroppedLights, LightSlot};
+
+// Bump this whenever a field changes meaning, and teach `MaskScene::upgrade` about it
+pub const MASK_SCENE_VERSION: u32 = 1;
+
+// Where the asset server loads from, scenes are saved back into it
+pub const ASSET_FOLDER: &str = "assets";
+pub const MASK_SCENE_ASSET: &str = "scenes/default.mask.ron";
+
+// The file on disk behind the asset path of a scene
+pub fn scene_file(asset_path: &Path) -> PathBuf {
+    Path::new(ASSET_FOLDER).join(asset_path)
+}
+
+#[derive(Serialize, Deserialize, TypeUuid, Clone, Debug, PartialEq)]
+#[uuid = "3f8a7c16-3e84-41b3-aeba-1bd773a35959"]
+pub struct MaskScene {
+    pub version: u32,
+    pub overlay: MaskOverlay,
+    #[serde(default)]
+    pub lights: Vec<MaskLight>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaskOverlay {
+    // alpha of the color is the overlay darkness
+    pub color: Color,
+    pub translation: Vec3,
+    #[serde(default = "default_rotation")]
+    pub rotation: Quat,
+    #[serde(default = "default_scale")]
+    pub scale: Vec3,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaskLight {
+    pub position: Vec2,
+    pub radius: f32,
+    #[serde(default = "default_light_color")]
+    pub color: Color,
+    #[serde(default = "default_softness")]
+    pub softness: f32,
+    #[serde(default)]
+    pub shape: LightShape,
+    // radians, turns every shape but the circle
+    #[serde(default)]
+    pub rotation: f32,
+}
+
+impl MaskLight {
+    // Radius only sizes circles, other shapes carry their own size
+    fn to_slot(&self) -> LightSlot {
+        if self.shape == LightShape::Circle {
+            let position = self.position.extend(self.radius).extend(self.softness);
+            return LightSlot::circle(position, self.color);
+        }
+        let matrix = Mat4::from_rotation_translation(
+            Quat::from_rotation_z(self.rotation),
+            self.position.extend(0.0),
+        );
+        self.shape.to_slot(matrix, self.softness, self.color)
+    }
+
+    fn from_slot(light: &LightSlot) -> Self {
+        let orientation = light.orientation;
+        Self {
+            position: light.position.truncate().truncate(),
+            radius: light.position.z,
+            color: light.color,
+            softness: light.position.w,
+            shape: LightShape::unpack(light),
+            // the world to local matrix of a rotation is its transpose
+            rotation: orientation.y.atan2(orientation.x),
+        }
+    }
+}
+
+fn default_rotation() -> Quat {
+    Quat::IDENTITY
+}
+
+fn default_scale() -> Vec3 {
+    Vec3::ONE
+}
+
+fn default_light_color() -> Color {
+    Color::rgba(0.0, 0.0, 0.0, 0.0)
+}
+
+fn default_softness() -> f32 {
+    crate::DEFAULT_SOFTNESS
+}
+
+#[derive(Debug)]
+pub enum MaskSceneError {
+    Io(std::io::Error),
+    Ron(ron::Error),
+    Json(serde_json::Error),
+    UnknownExtension(String),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for MaskSceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaskSceneError::Io(err) => write!(f, "io error: {}", err),
+            MaskSceneError::Ron(err) => write!(f, "ron error: {}", err),
+            MaskSceneError::Json(err) => write!(f, "json error: {}", err),
+            MaskSceneError::UnknownExtension(path) => {
+                write!(f, "{} is neither a .ron nor a .json file", path)
+            }
+            MaskSceneError::UnsupportedVersion(version) => write!(
+                f,
+                "scene version {} is newer than the supported version {}",
+                version, MASK_SCENE_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MaskSceneError {}
+
+impl From<std::io::Error> for MaskSceneError {
+    fn from(err: std::io::Error) -> Self {
+        MaskSceneError::Io(err)
+    }
+}
+
+impl From<ron::Error> for MaskSceneError {
+    fn from(err: ron::Error) -> Self {
+        MaskSceneError::Ron(err)
+    }
+}
+
+impl From<serde_json::Error> for MaskSceneError {
+    fn from(err: serde_json::Error) -> Self {
+        MaskSceneError::Json(err)
+    }
+}
+
+enum SceneFormat {
+    Ron,
+    Json,
+}
+
+impl SceneFormat {
+    fn from_path(path: &Path) -> Result<Self, MaskSceneError> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ron") => Ok(SceneFormat::Ron),
+            Some("json") => Ok(SceneFormat::Json),
+            _ => Err(MaskSceneError::UnknownExtension(path.display().to_string())),
+        }
+    }
+}
+
+impl MaskScene {
+    pub fn from_input(
+        input: &CoolMaterialUniformInput,
+        transform: &Transform,
+        dropped: &DroppedLights,
+    ) -> Self {
+        // lights of `LightSource` entities belong to the entities, not the scene
+        let lights = input
+            .own_lights()
+            .chain(dropped.lights.iter().copied())
+            .map(|light| MaskLight::from_slot(&light))
+            .collect();
+
+        Self {
+            version: MASK_SCENE_VERSION,
+            overlay: MaskOverlay {
+                color: input.color,
+                translation: transform.translation,
+                rotation: transform.rotation,
+                scale: transform.scale,
+            },
+            lights,
+        }
+    }
+
+    // Lights are packed into the first slots, the rest are cleared. Lights past
+    // MAX_LIGHTS don't fit in the uniform and end up in `dropped` instead
+    pub fn apply(
+        &self,
+        input: &mut CoolMaterialUniformInput,
+        transform: &mut Transform,
+        dropped: &mut DroppedLights,
+    ) {
+        *input = CoolMaterialUniformInput {
+            color: self.overlay.color,
+            ..Default::default()
+        };
+        dropped.lights.clear();
+        for light in self.lights.iter() {
+            let slot = light.to_slot();
+            if !input.push_slot(&slot) {
+                dropped.lights.push(slot);
+            }
+        }
+        if !dropped.lights.is_empty() {
+            warn!(
+                "mask scene has {} lights, {} did not fit and were dropped",
+                self.lights.len(),
+                dropped.lights.len()
+            );
+        }
+
+        transform.translation = self.overlay.translation;
+        transform.rotation = self.overlay.rotation;
+        transform.scale = self.overlay.scale;
+    }
+
+    pub fn from_ron(text: &str) -> Result<Self, MaskSceneError> {
+        ron::from_str::<MaskScene>(text)?.upgrade()
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, MaskSceneError> {
+        serde_json::from_str::<MaskScene>(text)?.upgrade()
+    }
+
+    pub fn to_ron(&self) -> Result<String, MaskSceneError> {
+        Ok(ron::ser::to_string_pretty(
+            self,
+            ron::ser::PrettyConfig::default(),
+        )?)
+    }
+
+    pub fn to_json(&self) -> Result<String, MaskSceneError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MaskSceneError> {
+        let path = path.as_ref();
+        let format = SceneFormat::from_path(path)?;
+        let text = fs::read_to_string(path)?;
+        match format {
+            SceneFormat::Ron => Self::from_ron(&text),
+            SceneFormat::Json => Self::from_json(&text),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), MaskSceneError> {
+        let path = path.as_ref();
+        let text = match SceneFormat::from_path(path)? {
+            SceneFormat::Ron => self.to_ron()?,
+            SceneFormat::Json => self.to_json()?,
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    // Migrates older files to the current layout, there is only v1 so far
+    fn upgrade(self) -> Result<Self, MaskSceneError> {
+        if self.version > MASK_SCENE_VERSION {
+            return Err(MaskSceneError::UnsupportedVersion(self.version));
+        }
+        Ok(Self {
+            version: MASK_SCENE_VERSION,
+            ..self
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct MaskSceneLoader;
+
+impl AssetLoader for MaskSceneLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let scene = MaskScene::from_ron(std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(scene));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["mask.ron"]
+    }
+}
+
+// Re-applies a scene every time its file is (re)loaded
+pub fn apply_loaded_mask_scenes(
+    mut scene_events: EventReader<AssetEvent<MaskScene>>,
+    scenes: Res<Assets<MaskScene>>,
+    mut overlay_query: Query<(
+        &Handle<MaskScene>,
+        &mut CoolMaterialUniformInput,
+        &mut Transform,
+        &mut DroppedLights,
+    )>,
+) {
+    for event in scene_events.iter() {
+        let changed = match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+            AssetEvent::Removed { .. } => continue,
+        };
+        let scene = match scenes.get(changed) {
+            Some(scene) => scene,
+            None => continue,
+        };
+        for (handle, mut colordata, mut transform, mut dropped) in overlay_query.iter_mut() {
+            if handle == changed {
+                scene.apply(&mut colordata, &mut transform, &mut dropped);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mask_composite::ShapeTree;
+
+    fn scene() -> MaskScene {
+        MaskScene {
+            version: MASK_SCENE_VERSION,
+            overlay: MaskOverlay {
+                color: Color::rgba(0.1, 0.0, 0.2, 0.75),
+                translation: Vec3::new(0.5, -0.25, 0.0),
+                rotation: Quat::from_rotation_z(0.3),
+                scale: Vec3::new(2.0, 1.0, 1.0),
+            },
+            lights: vec![
+                MaskLight {
+                    position: Vec2::new(-0.2, 0.1),
+                    radius: 0.05,
+                    color: Color::rgba(1.0, 0.7, 0.3, 0.2),
+                    softness: 0.5,
+                    shape: LightShape::Circle,
+                    rotation: 0.0,
+                },
+                MaskLight {
+                    position: Vec2::new(0.3, 0.0),
+                    radius: 0.0,
+                    color: default_light_color(),
+                    softness: 0.02,
+                    shape: LightShape::Composite(
+                        ShapeTree::shape(LightShape::RoundedBox {
+                            half_size: Vec2::new(0.1, 0.05),
+                            corner_radius: 0.01,
+                        })
+                        .subtract(ShapeTree::shape(LightShape::Circle).at(Vec2::X * 0.05)),
+                    ),
+                    rotation: 0.5,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn scenes_round_trip_through_ron_and_json() {
+        let scene = scene();
+        assert_eq!(
+            MaskScene::from_ron(&scene.to_ron().unwrap()).unwrap(),
+            scene
+        );
+        assert_eq!(
+            MaskScene::from_json(&scene.to_json().unwrap()).unwrap(),
+            scene
+        );
+    }
+
+    #[test]
+    fn older_scenes_are_upgraded_and_newer_ones_rejected() {
+        // a version 0 file, written before the optional fields existed
+        let old = "(
+            version: 0,
+            overlay: (
+                color: Rgba(red: 0.0, green: 0.0, blue: 0.0, alpha: 0.5),
+                translation: (0.0, 0.0, 0.0),
+            ),
+            lights: [(position: (0.1, 0.2), radius: 0.05)],
+        )";
+        let upgraded = MaskScene::from_ron(old).unwrap();
+        assert_eq!(upgraded.version, MASK_SCENE_VERSION);
+        assert_eq!(upgraded.overlay.rotation, Quat::IDENTITY);
+        assert_eq!(upgraded.overlay.scale, Vec3::ONE);
+        assert_eq!(
+            upgraded.lights,
+            vec![MaskLight {
+                position: Vec2::new(0.1, 0.2),
+                radius: 0.05,
+                color: default_light_color(),
+                softness: crate::DEFAULT_SOFTNESS,
+                shape: LightShape::Circle,
+                rotation: 0.0,
+            }]
+        );
+
+        let newer = MaskScene {
+            version: MASK_SCENE_VERSION + 1,
+            ..scene()
+        };
+        for result in [
+            MaskScene::from_ron(&newer.to_ron().unwrap()),
+            MaskScene::from_json(&newer.to_json().unwrap()),
+        ] {
+            assert!(matches!(
+                result,
+                Err(MaskSceneError::UnsupportedVersion(version)) if version == MASK_SCENE_VERSION + 1
+            ));
+        }
+    }
+
+    #[test]
+    fn committed_scene_loads() {
+        MaskScene::load(scene_file(Path::new(MASK_SCENE_ASSET))).unwrap();
+    }
+}
@@ -0,0 +1,489 @@
+// Shapes a light can have besides the original circle.
+//
+// Every shape but the circle and the cone is a signed distance field, negative
+// inside and measured in the light's local space: mask_functions.wgsl evaluates
+// it in the shader, mask_sample.rs on the CPU. The cone combines a falloff along
+// its range with one across its angle instead. A shaped light fills its slot as
+//   position     (x, y, extent, softness)
+//   shape        (a, b, c, kind), the parameters of the variant
+//   orientation  world to local 2x2 matrix, row major
+//   polygon      up to MAX_POLYGON_VERTICES vertices, two per vec4
+//   program      the compiled `ShapeTree` of a composite
+// where extent is the world radius around (x, y) that holds the shape and its
+// soft band, for sizing the rasterized quads and the debug view, and softness
+// is the half width of the soft band in local units. Circles keep the original
+// (x, y, radius, softness) packing and ignore the rest of the slot.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mask_composite::{ShapeProgram, ShapeTree, ShapeTreeError, EMPTY_SHAPE_PROGRAM},
+    LightSlot, EMPTY_LIGHT_POLYGON,
+};
+
+pub const MAX_POLYGON_VERTICES: usize = 8;
+pub const POLYGON_VEC4S: usize = MAX_POLYGON_VERTICES / 2;
+
+// shape.w of a slot, mask_shape_distance() in mask_functions.wgsl matches on the same numbers
+pub const SHAPE_CIRCLE: u32 = 0;
+pub const SHAPE_ROUNDED_BOX: u32 = 1;
+pub const SHAPE_ELLIPSE: u32 = 2;
+pub const SHAPE_CAPSULE: u32 = 3;
+pub const SHAPE_RING: u32 = 4;
+pub const SHAPE_POLYGON: u32 = 5;
+pub const SHAPE_COMPOSITE: u32 = 6;
+pub const SHAPE_CONE: u32 = 7;
+
+// The shader def of every kind but the circle, see `LightShapes`
+const SHAPE_DEFS: [(u32, &str); 7] = [
+    (SHAPE_ROUNDED_BOX, "SHAPE_ROUNDED_BOX"),
+    (SHAPE_ELLIPSE, "SHAPE_ELLIPSE"),
+    (SHAPE_CAPSULE, "SHAPE_CAPSULE"),
+    (SHAPE_RING, "SHAPE_RING"),
+    (SHAPE_POLYGON, "SHAPE_POLYGON"),
+    (SHAPE_COMPOSITE, "SHAPE_COMPOSITE"),
+    (SHAPE_CONE, "SHAPE_CONE"),
+];
+
+// Keeps the soft band from collapsing, smoothstep is undefined for equal edges
+const MIN_SOFTNESS: f32 = 1e-4;
+
+// The kinds of shapes among some lights, one bit per kind. Each kind but the
+// circle has a branch in mask_functions.wgsl behind a shader def of its own,
+// so a mask only compiles the distance functions it uses. Lights of a kind
+// left out light nothing
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct LightShapes(u8);
+
+impl LightShapes {
+    // The kinds of the lights in `shapes` and of the shapes in the programs of
+    // their composites
+    pub fn of_slots(shapes: &[Vec4], programs: &[ShapeProgram]) -> Self {
+        let mut kinds = Self::default();
+        for shape in shapes {
+            kinds.insert(shape.w as u32);
+            if shape.w as u32 != SHAPE_COMPOSITE {
+                continue;
+            }
+            if let Some(program) = programs.get(shape.y as usize) {
+                for instruction in program.chunks(2).take(shape.x as usize) {
+                    let op = instruction[0].w as u32;
+                    if op < SHAPE_POLYGON {
+                        kinds.insert(op);
+                    }
+                }
+            }
+        }
+        kinds
+    }
+
+    pub fn insert(&mut self, kind: u32) {
+        self.0 |= 1 << kind;
+    }
+
+    pub fn contains(&self, kind: u32) -> bool {
+        self.0 & (1 << kind) != 0
+    }
+
+    pub fn shader_defs(&self) -> impl Iterator<Item = String> + '_ {
+        SHAPE_DEFS
+            .iter()
+            .filter(|(kind, _)| self.contains(*kind))
+            .map(|(_, def)| def.to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum LightShape {
+    // the original light: sized by the radius in a scene, and a circle of
+    // diameter 1 scaled by the Transform on a `LightSource`
+    #[default]
+    Circle,
+    RoundedBox {
+        half_size: Vec2,
+        corner_radius: f32,
+    },
+    Ellipse {
+        radii: Vec2,
+    },
+    // along the local x axis
+    Capsule {
+        half_length: f32,
+        radius: f32,
+    },
+    Ring {
+        radius: f32,
+        thickness: f32,
+    },
+    // convex, with 3 to MAX_POLYGON_VERTICES vertices and no two in a row
+    // in the same place
+    Polygon {
+        vertices: Vec<Vec2>,
+    },
+    // shapes combined with boolean operations, see mask_composite.rs
+    Composite(ShapeTree),
+    // a vision cone along the local x axis, `angle` wide and `range` long.
+    // The light's softness blurs its end, `angular_softness` its sides, both
+    // angles in radians
+    Cone {
+        angle: f32,
+        range: f32,
+        angular_softness: f32,
+    },
+}
+
+// The entries a shape takes in a slot
+#[derive(Clone, Copy)]
+pub struct PackedShape {
+    pub shape: Vec4,
+    pub polygon: [Vec4; POLYGON_VEC4S],
+    pub program: ShapeProgram,
+}
+
+impl PackedShape {
+    // What a composite that doesn't compile packs to, an empty program lights
+    // nothing
+    pub fn empty_composite() -> Self {
+        Self {
+            shape: Vec4::new(0.0, 0.0, 0.0, SHAPE_COMPOSITE as f32),
+            polygon: EMPTY_LIGHT_POLYGON,
+            program: EMPTY_SHAPE_PROGRAM,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ShapeError {
+    Tree(ShapeTreeError),
+    TooFewVertices(usize),
+    TooManyVertices(usize),
+    // the edge from this vertex to the next has no length, the shader
+    // divides by it
+    DegenerateEdge(usize),
+}
+
+impl std::fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ShapeError::Tree(err) => write!(f, "{}", err),
+            ShapeError::TooFewVertices(count) => {
+                write!(f, "polygon needs at least 3 vertices, got {}", count)
+            }
+            ShapeError::TooManyVertices(count) => write!(
+                f,
+                "polygon has {} vertices, at most {} fit into a slot",
+                count, MAX_POLYGON_VERTICES
+            ),
+            ShapeError::DegenerateEdge(i) => {
+                write!(f, "polygon vertex {} is in the same place as the next", i)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ShapeError {}
+
+impl From<ShapeTreeError> for ShapeError {
+    fn from(err: ShapeTreeError) -> Self {
+        ShapeError::Tree(err)
+    }
+}
+
+impl LightShape {
+    // Compiles composite trees and checks polygons, lights that are placed
+    // every frame should keep the result instead of packing again
+    pub fn try_pack(&self) -> Result<PackedShape, ShapeError> {
+        let mut polygon = EMPTY_LIGHT_POLYGON;
+        let mut program = EMPTY_SHAPE_PROGRAM;
+        let shape = match self {
+            LightShape::Circle => Vec4::ZERO,
+            LightShape::RoundedBox {
+                half_size,
+                corner_radius,
+            } => half_size
+                .extend(*corner_radius)
+                .extend(SHAPE_ROUNDED_BOX as f32),
+            LightShape::Ellipse { radii } => radii.extend(0.0).extend(SHAPE_ELLIPSE as f32),
+            LightShape::Capsule {
+                half_length,
+                radius,
+            } => Vec4::new(*half_length, *radius, 0.0, SHAPE_CAPSULE as f32),
+            LightShape::Ring { radius, thickness } => {
+                Vec4::new(*radius, *thickness, 0.0, SHAPE_RING as f32)
+            }
+            LightShape::Polygon { vertices } => {
+                let count = vertices.len();
+                if count < 3 {
+                    return Err(ShapeError::TooFewVertices(count));
+                }
+                if count > MAX_POLYGON_VERTICES {
+                    return Err(ShapeError::TooManyVertices(count));
+                }
+                if let Some(i) = (0..count).find(|&i| vertices[i] == vertices[(i + 1) % count]) {
+                    return Err(ShapeError::DegenerateEdge(i));
+                }
+                for (i, vertex) in vertices.iter().enumerate() {
+                    let packed = &mut polygon[i / 2];
+                    if i % 2 == 0 {
+                        packed.x = vertex.x;
+                        packed.y = vertex.y;
+                    } else {
+                        packed.z = vertex.x;
+                        packed.w = vertex.y;
+                    }
+                }
+                Vec4::new(count as f32, 0.0, 0.0, SHAPE_POLYGON as f32)
+            }
+            // y is the program chunk, picked when the light gets a slot
+            LightShape::Composite(tree) => {
+                let (compiled, len) = tree.compile()?;
+                program = compiled;
+                Vec4::new(len as f32, 0.0, 0.0, SHAPE_COMPOSITE as f32)
+            }
+            LightShape::Cone {
+                angle,
+                range,
+                angular_softness,
+            } => Vec4::new(
+                angle * 0.5,
+                *range,
+                angular_softness.max(MIN_SOFTNESS),
+                SHAPE_CONE as f32,
+            ),
+        };
+        Ok(PackedShape {
+            shape,
+            polygon,
+            program,
+        })
+    }
+
+    // `try_pack` for lights packed once, a shape that doesn't pack is logged
+    // and lights nothing
+    pub fn pack(&self) -> PackedShape {
+        self.try_pack().unwrap_or_else(|err| {
+            error!("{}", err);
+            PackedShape::empty_composite()
+        })
+    }
+
+    // Reverse of `pack`
+    pub fn unpack(light: &LightSlot) -> Self {
+        let shape = light.shape;
+        match shape.w as u32 {
+            SHAPE_POLYGON => LightShape::Polygon {
+                vertices: (0..shape.x as usize)
+                    .map(|i| polygon_vertex(&light.polygon, i))
+                    .collect(),
+            },
+            SHAPE_COMPOSITE => match ShapeTree::decompile(&light.program, shape.x as usize) {
+                Some(tree) => LightShape::Composite(tree),
+                None => LightShape::Circle,
+            },
+            _ => Self::unpack_sdf(shape),
+        }
+    }
+
+    // `unpack` for the shapes that fit in the shape entry alone
+    pub fn unpack_sdf(shape: Vec4) -> Self {
+        match shape.w as u32 {
+            SHAPE_ROUNDED_BOX => LightShape::RoundedBox {
+                half_size: shape.truncate().truncate(),
+                corner_radius: shape.z,
+            },
+            SHAPE_ELLIPSE => LightShape::Ellipse {
+                radii: shape.truncate().truncate(),
+            },
+            SHAPE_CAPSULE => LightShape::Capsule {
+                half_length: shape.x,
+                radius: shape.y,
+            },
+            SHAPE_RING => LightShape::Ring {
+                radius: shape.x,
+                thickness: shape.y,
+            },
+            SHAPE_CONE => LightShape::Cone {
+                angle: shape.x * 2.0,
+                range: shape.y,
+                angular_softness: shape.z,
+            },
+            _ => LightShape::Circle,
+        }
+    }
+
+    // Radius around the local origin that holds the whole shape
+    pub fn extent(&self) -> f32 {
+        match self {
+            LightShape::Circle => 0.5,
+            LightShape::RoundedBox { half_size, .. } => half_size.length(),
+            LightShape::Ellipse { radii } => radii.max_element(),
+            LightShape::Capsule {
+                half_length,
+                radius,
+            } => half_length + radius,
+            LightShape::Ring { radius, thickness } => radius + thickness * 0.5,
+            LightShape::Polygon { vertices } => vertices
+                .iter()
+                .take(MAX_POLYGON_VERTICES)
+                .map(|vertex| vertex.length())
+                .fold(0.0, f32::max),
+            LightShape::Composite(tree) => tree.extent(),
+            LightShape::Cone { range, .. } => *range,
+        }
+    }
+
+    // The slot of this shape placed by `matrix`, the light's local to world
+    // transform. Only the 2d part of it is used
+    pub fn to_slot(&self, matrix: Mat4, softness: f32, color: Color) -> LightSlot {
+        self.place(&self.pack(), matrix, softness, color)
+    }
+
+    // `to_slot` with the shape packed ahead of time by `try_pack`
+    pub fn place(
+        &self,
+        packed: &PackedShape,
+        matrix: Mat4,
+        softness: f32,
+        color: Color,
+    ) -> LightSlot {
+        let center = matrix.w_axis.truncate().truncate();
+        let x_axis = matrix.x_axis.truncate().truncate();
+        let y_axis = matrix.y_axis.truncate().truncate();
+        if *self == LightShape::Circle {
+            // the shader compares against the squared diameter
+            let diameter = x_axis.length();
+            return LightSlot::circle(center.extend(diameter * diameter).extend(softness), color);
+        }
+
+        let to_local = Mat2::from_cols(x_axis, y_axis).inverse();
+        let scale = x_axis.length().max(y_axis.length());
+        let PackedShape {
+            shape,
+            polygon,
+            program,
+        } = *packed;
+        LightSlot {
+            position: center
+                .extend((self.extent() + softness) * scale)
+                .extend(softness.max(MIN_SOFTNESS)),
+            color,
+            shape,
+            orientation: Vec4::new(
+                to_local.x_axis.x,
+                to_local.y_axis.x,
+                to_local.x_axis.y,
+                to_local.y_axis.y,
+            ),
+            polygon,
+            program,
+        }
+    }
+}
+
+pub fn polygon_vertex(polygon: &[Vec4; POLYGON_VEC4S], i: usize) -> Vec2 {
+    let packed = polygon[i / 2];
+    if i % 2 == 0 {
+        Vec2::new(packed.x, packed.y)
+    } else {
+        Vec2::new(packed.z, packed.w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_round_trips() {
+        for shape in [
+            LightShape::Circle,
+            LightShape::RoundedBox {
+                half_size: Vec2::new(0.2, 0.1),
+                corner_radius: 0.05,
+            },
+            LightShape::Ellipse {
+                radii: Vec2::new(0.3, 0.1),
+            },
+            LightShape::Capsule {
+                half_length: 0.2,
+                radius: 0.05,
+            },
+            LightShape::Ring {
+                radius: 0.2,
+                thickness: 0.04,
+            },
+            LightShape::Polygon {
+                vertices: vec![
+                    Vec2::new(0.0, 0.1),
+                    Vec2::new(-0.1, -0.1),
+                    Vec2::new(0.1, -0.1),
+                ],
+            },
+            LightShape::Composite(
+                ShapeTree::shape(LightShape::Circle).union(
+                    ShapeTree::shape(LightShape::Ring {
+                        radius: 0.2,
+                        thickness: 0.04,
+                    })
+                    .at(Vec2::new(0.1, 0.0)),
+                ),
+            ),
+            LightShape::Cone {
+                angle: 0.8,
+                range: 0.4,
+                angular_softness: 0.1,
+            },
+        ] {
+            let light = shape.to_slot(Mat4::IDENTITY, 0.02, Color::WHITE);
+            assert_eq!(LightShape::unpack(&light), shape);
+        }
+    }
+
+    #[test]
+    fn broken_polygons_are_rejected() {
+        let polygon = |vertices: Vec<Vec2>| LightShape::Polygon { vertices }.try_pack().err();
+        assert_eq!(
+            polygon(vec![Vec2::ZERO, Vec2::X]),
+            Some(ShapeError::TooFewVertices(2))
+        );
+        let ring = (0..MAX_POLYGON_VERTICES + 2)
+            .map(|i| Vec2::new((i as f32).cos(), (i as f32).sin()))
+            .collect();
+        assert_eq!(
+            polygon(ring),
+            Some(ShapeError::TooManyVertices(MAX_POLYGON_VERTICES + 2))
+        );
+        assert_eq!(
+            polygon(vec![Vec2::ZERO, Vec2::X, Vec2::X, Vec2::Y]),
+            Some(ShapeError::DegenerateEdge(1))
+        );
+        // closing the outline by repeating the first vertex leaves a zero edge too
+        assert_eq!(
+            polygon(vec![Vec2::ZERO, Vec2::X, Vec2::Y, Vec2::ZERO]),
+            Some(ShapeError::DegenerateEdge(3))
+        );
+        assert_eq!(polygon(vec![Vec2::ZERO, Vec2::X, Vec2::Y]), None);
+
+        let packed = LightShape::Polygon {
+            vertices: vec![Vec2::ONE; 3],
+        }
+        .pack();
+        assert_eq!(packed.shape.w as u32, SHAPE_COMPOSITE);
+        assert_eq!(packed.shape.x, 0.0);
+    }
+
+    #[test]
+    fn broken_trees_pack_to_nothing() {
+        let shape = LightShape::Composite(ShapeTree::shape(LightShape::Polygon {
+            vertices: vec![Vec2::ZERO; 3],
+        }));
+        assert_eq!(
+            shape.try_pack().err(),
+            Some(ShapeError::Tree(ShapeTreeError::UnsupportedLeaf))
+        );
+        let packed = shape.pack();
+        assert_eq!(packed.shape.x, 0.0);
+        assert_eq!(packed.program, EMPTY_SHAPE_PROGRAM);
+    }
+}
@@ -0,0 +1,405 @@
+// Lights placed by entities instead of pushed into a mask by hand.
+//
+// An entity with a `LightSource` and a `GlobalTransform` adds one light to the
+// mask entity `mask`: its shape at the entity's translation, turned by its
+// rotation and stretched by its scale. As the lights are read after transforms
+// are propagated, a source can be the child of a moving or turning entity.
+// Every frame the lights of all sources are rewritten into the tail of the
+// mask's slots, behind the lights pushed by hand (see
+// `CoolMaterialUniformInput`), so moving or despawning a source needs nothing
+// else. The input is only touched when a source changed.
+//
+// A `LightBeam` is a capsule stretched between two other entities instead, for
+// lasers and light bridges. It follows both ends, and when the first of them
+// goes away it fades out where it was last seen and is despawned, right away
+// in apps without the `LightFadePlugin`. Both can fade in and out with a
+// `LightFade`, see mask_fade.rs.
+
+use bevy::{prelude::*, transform::TransformSystem, utils::HashMap};
+
+use crate::{
+    mask_fade::{Easing, FadeDirection, LightFade, LightFaded},
+    mask_shape::{LightShape, PackedShape},
+    CoolMaterialUniformInput, LightSlot, DEFAULT_SOFTNESS,
+};
+
+// Softness of the shapes other than the circle, in local units
+const DEFAULT_SHAPE_SOFTNESS: f32 = 0.02;
+const DEFAULT_BREAK_FADE: f32 = 0.2;
+
+pub struct LightSourcePlugin;
+
+impl Plugin for LightSourcePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LightSourceSlots>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                sync_light_sources
+                    .label(LightSourceSystem)
+                    .after(TransformSystem::TransformPropagate),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, despawn_broken_beams);
+    }
+}
+
+// The system that writes the lights of the sources into their masks, after
+// transforms are propagated. Order systems that read the mask after it
+#[derive(SystemLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LightSourceSystem;
+
+// Where the light of every source and beam went among the source lights of
+// its mask, see `CoolMaterialUniformInput::source_light`. Lights that did not
+// fit into the mask or are faded out completely have none
+#[derive(Default)]
+pub struct LightSourceSlots {
+    slots: HashMap<Entity, usize>,
+}
+
+impl LightSourceSlots {
+    pub fn get(&self, source: Entity) -> Option<usize> {
+        self.slots.get(&source).copied()
+    }
+}
+
+#[derive(Component, Clone, Debug)]
+pub struct LightSource {
+    // the entity with the `CoolMaterialUniformInput` this light is added to
+    pub mask: Entity,
+    pub shape: LightShape,
+    pub color: Color,
+    // relative to the radius for circles, in local units for the other shapes
+    pub softness: f32,
+}
+
+impl LightSource {
+    pub fn new(mask: Entity, shape: LightShape) -> Self {
+        let softness = match shape {
+            LightShape::Circle => DEFAULT_SOFTNESS,
+            _ => DEFAULT_SHAPE_SOFTNESS,
+        };
+        Self {
+            mask,
+            shape,
+            color: Color::rgba(0.0, 0.0, 0.0, 0.0),
+            softness,
+        }
+    }
+
+    // The light this source adds to its mask when placed by `transform`
+    pub fn slot(&self, transform: &GlobalTransform) -> LightSlot {
+        self.shape
+            .to_slot(transform.compute_matrix(), self.softness, self.color)
+    }
+
+    // `slot` with the shape packed ahead of time, shrunk around its origin by
+    // `fade`, the size from 0 to 1
+    pub fn placed_slot(
+        &self,
+        packed: &PackedShape,
+        transform: &GlobalTransform,
+        fade: f32,
+    ) -> LightSlot {
+        let matrix = transform.compute_matrix() * Mat4::from_scale(Vec3::splat(fade));
+        self.shape.place(packed, matrix, self.softness, self.color)
+    }
+}
+
+#[derive(Component, Clone, Debug)]
+pub struct LightBeam {
+    pub mask: Entity,
+    // the entities whose translations are the two ends
+    pub from: Entity,
+    pub to: Entity,
+    pub width: f32,
+    pub color: Color,
+    // in world units, around the edge of the capsule
+    pub softness: f32,
+    // seconds the beam takes to fade out once it lost an end
+    pub break_fade: f32,
+}
+
+impl LightBeam {
+    pub fn new(mask: Entity, from: Entity, to: Entity, width: f32) -> Self {
+        Self {
+            mask,
+            from,
+            to,
+            width,
+            color: Color::rgba(0.0, 0.0, 0.0, 0.0),
+            softness: DEFAULT_SHAPE_SOFTNESS,
+            break_fade: DEFAULT_BREAK_FADE,
+        }
+    }
+
+    // The light of a beam between `from` and `to`
+    pub fn slot(&self, from: Vec2, to: Vec2) -> LightSlot {
+        self.faded_slot(from, to, 1.0)
+    }
+
+    // `slot` shrunk around the middle of the beam by `fade`, the size from 0
+    // to 1, length, width and softness alike
+    pub fn faded_slot(&self, from: Vec2, to: Vec2, fade: f32) -> LightSlot {
+        let axis = to - from;
+        let matrix = Mat4::from_scale_rotation_translation(
+            Vec3::splat(fade),
+            Quat::from_rotation_z(axis.y.atan2(axis.x)),
+            ((from + to) * 0.5).extend(0.0),
+        );
+        let shape = LightShape::Capsule {
+            half_length: axis.length() * 0.5,
+            radius: self.width * 0.5,
+        };
+        shape.to_slot(matrix, self.softness, self.color)
+    }
+}
+
+fn sync_light_sources(
+    source_query: Query<(
+        Entity,
+        &LightSource,
+        ChangeTrackers<LightSource>,
+        &GlobalTransform,
+        Option<&LightFade>,
+    )>,
+    beam_query: Query<(Entity, &LightBeam, Option<&LightFade>)>,
+    endpoint_query: Query<&GlobalTransform>,
+    mut mask_query: Query<(Entity, &mut CoolMaterialUniformInput)>,
+    mut source_slots: ResMut<LightSourceSlots>,
+    mut lights: Local<HashMap<Entity, Vec<LightSlot>>>,
+    // the source or beam of each light in `lights`
+    mut owners: Local<HashMap<Entity, Vec<Entity>>>,
+    mut dropped: Local<HashMap<Entity, usize>>,
+    mut packed_shapes: Local<HashMap<Entity, PackedShape>>,
+    // where the ends of each beam were last seen, for a beam that lost one
+    mut beam_ends: Local<HashMap<Entity, (Vec2, Vec2)>>,
+) {
+    lights.values_mut().for_each(Vec::clear);
+    owners.values_mut().for_each(Vec::clear);
+    packed_shapes.retain(|entity, _| source_query.get(*entity).is_ok());
+    beam_ends.retain(|entity, _| beam_query.get(*entity).is_ok());
+    for (entity, source, tracker, transform, fade) in source_query.iter() {
+        // shapes are packed, and trees compiled, only when the source changed,
+        // so a broken tree is reported once
+        if tracker.is_changed() || !packed_shapes.contains_key(&entity) {
+            let packed = source.shape.try_pack().unwrap_or_else(|err| {
+                error!("light source {:?}: {}", entity, err);
+                PackedShape::empty_composite()
+            });
+            packed_shapes.insert(entity, packed);
+        }
+        let factor = fade.map_or(1.0, LightFade::factor);
+        // a light shrunk to nothing has no inverse orientation
+        if factor <= 0.0 {
+            continue;
+        }
+        let slot = source.placed_slot(&packed_shapes[&entity], transform, factor);
+        lights.entry(source.mask).or_default().push(slot);
+        owners.entry(source.mask).or_default().push(entity);
+    }
+    for (entity, beam, fade) in beam_query.iter() {
+        if let (Ok(from), Ok(to)) = (endpoint_query.get(beam.from), endpoint_query.get(beam.to)) {
+            beam_ends.insert(
+                entity,
+                (from.translation().truncate(), to.translation().truncate()),
+            );
+        }
+        let factor = fade.map_or(1.0, LightFade::factor);
+        if factor <= 0.0 {
+            continue;
+        }
+        // a beam that lost an end stays where it was while
+        // despawn_broken_beams fades it out
+        if let Some(&(from, to)) = beam_ends.get(&entity) {
+            lights
+                .entry(beam.mask)
+                .or_default()
+                .push(beam.faded_slot(from, to, factor));
+            owners.entry(beam.mask).or_default().push(entity);
+        }
+    }
+
+    source_slots.slots.clear();
+    for (entity, mut input) in mask_query.iter_mut() {
+        let wanted = lights.get(&entity).map_or(&[][..], Vec::as_slice);
+        let owners = owners.get(&entity).map_or(&[][..], Vec::as_slice);
+        if input.source_lights().eq(wanted.iter().copied()) {
+            // every light is where it was
+            source_slots
+                .slots
+                .extend(owners.iter().copied().zip(0..wanted.len()));
+            continue;
+        }
+        let placed = input.set_source_lights(wanted);
+        source_slots.slots.extend(
+            owners
+                .iter()
+                .zip(&placed)
+                .filter_map(|(&owner, index)| Some((owner, (*index)?))),
+        );
+        let count = placed.iter().filter(|index| index.is_none()).count();
+        // only when the number changes, not every frame a source moves
+        if dropped.insert(entity, count).unwrap_or(0) != count && count > 0 {
+            warn!(
+                "{} light sources of {:?} did not fit into the free slots",
+                count, entity
+            );
+        }
+    }
+}
+
+// Fades out the beams that lost an end, a beam fading in turns around from
+// where it is. The fade despawns them when done. Without the `LightFadePlugin`
+// (which adds the `LightFaded` events) nothing would advance the fade, so the
+// beams are despawned at once
+fn despawn_broken_beams(
+    mut commands: Commands,
+    mut beam_query: Query<(Entity, &LightBeam, Option<&mut LightFade>)>,
+    endpoint_query: Query<(), With<GlobalTransform>>,
+    faded_events: Option<Res<Events<LightFaded>>>,
+) {
+    for (entity, beam, fade) in beam_query.iter_mut() {
+        if endpoint_query.get(beam.from).is_ok() && endpoint_query.get(beam.to).is_ok() {
+            continue;
+        }
+        if faded_events.is_none() {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+        match fade {
+            Some(fade) if fade.direction == FadeDirection::Out => {}
+            Some(mut fade) => *fade = fade.reversed(),
+            None => {
+                commands
+                    .entity(entity)
+                    .insert(LightFade::fade_out(beam.break_fade, Easing::EaseIn));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mask_fade::LightFadePlugin;
+    use bevy::utils::{Duration, Instant};
+
+    fn app() -> App {
+        let mut app = App::default();
+        app.add_plugin(TransformPlugin)
+            .add_plugin(LightSourcePlugin);
+        app
+    }
+
+    fn spawn_at(app: &mut App, transform: Transform) -> Entity {
+        app.world
+            .spawn()
+            .insert_bundle(TransformBundle::from_transform(transform))
+            .id()
+    }
+
+    #[test]
+    fn sources_follow_their_parents() {
+        let mut app = app();
+        let mask = app
+            .world
+            .spawn()
+            .insert(CoolMaterialUniformInput::default())
+            .id();
+        let parent_transform = Transform::from_xyz(0.1, 0.0, 0.0)
+            .with_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2))
+            .with_scale(Vec3::splat(2.0));
+        let child_transform = Transform::from_xyz(0.2, 0.0, 0.0).with_scale(Vec3::splat(0.1));
+        let parent = spawn_at(&mut app, parent_transform);
+        let source = LightSource::new(mask, LightShape::Circle);
+        let child = spawn_at(&mut app, child_transform);
+        app.world.entity_mut(child).insert(source.clone());
+        app.world.entity_mut(parent).push_children(&[child]);
+
+        app.update();
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        let global = GlobalTransform::from(parent_transform).mul_transform(child_transform);
+        let lights: Vec<LightSlot> = input.source_lights().collect();
+        assert_eq!(lights, vec![source.slot(&global)]);
+        // a circle of 0.1 at 0.2 along the parent's turned and doubled x axis
+        assert!(
+            (lights[0].position - Vec4::new(0.1, 0.4, 0.04, DEFAULT_SOFTNESS))
+                .abs()
+                .max_element()
+                < 1e-6
+        );
+    }
+
+    #[test]
+    fn beams_follow_their_ends_and_go_without_them() {
+        let mut app = app();
+        let mask = app
+            .world
+            .spawn()
+            .insert(CoolMaterialUniformInput::default())
+            .id();
+        let from = spawn_at(&mut app, Transform::from_xyz(-0.2, 0.0, 0.0));
+        let to = spawn_at(&mut app, Transform::from_xyz(0.2, 0.1, 0.0));
+        let beam = LightBeam::new(mask, from, to, 0.05);
+        let beam_entity = app.world.spawn().insert(beam.clone()).id();
+
+        app.update();
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        let expected = beam.slot(Vec2::new(-0.2, 0.0), Vec2::new(0.2, 0.1));
+        assert_eq!(input.source_lights().collect::<Vec<_>>(), vec![expected]);
+
+        app.world.get_mut::<Transform>(to).unwrap().translation.y = -0.1;
+        app.update();
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        let expected = beam.slot(Vec2::new(-0.2, 0.0), Vec2::new(0.2, -0.1));
+        assert_eq!(input.source_lights().collect::<Vec<_>>(), vec![expected]);
+
+        // without the LightFadePlugin the beam goes at once
+        app.world.despawn(from);
+        app.update();
+        assert!(app.world.get_entity(beam_entity).is_none());
+        app.update();
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        assert_eq!(input.source_lights().count(), 0);
+        assert_eq!(
+            app.world.resource::<LightSourceSlots>().get(beam_entity),
+            None
+        );
+    }
+
+    #[test]
+    fn broken_beams_fade_before_they_go() {
+        let mut app = app();
+        app.add_plugin(LightFadePlugin).init_resource::<Time>();
+        let start = Instant::now();
+        app.world.resource_mut::<Time>().update_with_instant(start);
+        let mask = app
+            .world
+            .spawn()
+            .insert(CoolMaterialUniformInput::default())
+            .id();
+        let from = spawn_at(&mut app, Transform::from_xyz(-0.2, 0.0, 0.0));
+        let to = spawn_at(&mut app, Transform::from_xyz(0.2, 0.1, 0.0));
+        let beam = LightBeam::new(mask, from, to, 0.05);
+        let beam_entity = app.world.spawn().insert(beam.clone()).id();
+        app.update();
+
+        app.world.despawn(from);
+        app.update();
+        let fade = app.world.get::<LightFade>(beam_entity).unwrap();
+        assert_eq!(fade.direction, FadeDirection::Out);
+
+        let mut now = start;
+        for _ in 0..3 {
+            now += Duration::from_secs_f32(beam.break_fade);
+            app.world.resource_mut::<Time>().update_with_instant(now);
+            app.update();
+        }
+        assert!(app.world.get_entity(beam_entity).is_none());
+        let input = app.world.get::<CoolMaterialUniformInput>(mask).unwrap();
+        assert_eq!(input.source_lights().count(), 0);
+        assert_eq!(
+            app.world.resource::<LightSourceSlots>().get(beam_entity),
+            None
+        );
+    }
+}
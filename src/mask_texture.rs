@@ -0,0 +1,577 @@
+// The lights of one mask, rendered into an `Image` once per frame so other
+// materials can sample it instead of evaluating every light per pixel.
+//
+// Every light is drawn as one instanced quad around its outer edge, so the
+// cost follows the area the lights cover. The quads are blended into the
+// texture: with `MaskBlend::Multiply` as premultiplied alpha, which gives the
+// same combined result as the loop in my_material_t_2.wgsl, with
+// `MaskBlend::Min` with a max blend on how lit each light makes a point.
+//
+// The texture covers the world rectangle `world_min..world_max` of
+// `MaskTexture`. Alpha holds how lit a point is, 0 fully masked and 1 fully
+// lit, the value `mask_sample::sample_lights` computes on the CPU. RGB holds
+// the light colors weighted by that, zero when the material has no tint.
+// Bind `MaskTexture::image` like any other image, with `#[texture(n)]` and
+// `#[sampler(m)]`, and sample it at
+//   uv = ((x - world_min.x) / width, (world_max.y - y) / height)
+// `MaskTextureMaterial` does this to draw the overlay. The alpha of a light
+// color has nowhere to go in the texture, so unlike the per-pixel overlay it
+// does not change the overlay's alpha.
+
+use bevy::{
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        main_graph,
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice},
+        Extract, RenderApp, RenderStage,
+    },
+    sprite::{Material2d, Material2dPlugin},
+};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    mask_composite::{ShapeProgram, MAX_SHAPE_PROGRAMS},
+    mask_shape::{LightShapes, POLYGON_VEC4S},
+    wgsl_struct::wgsl_struct,
+    CoolMaterial, CoolMaterialKey, CoolMaterialUniformInput, MaskBlend,
+};
+
+pub const MASK_TEXTURE: &str = "mask_texture";
+pub const MASK_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+pub const MASK_TEXTURE_SIZE: u32 = 512;
+
+pub struct MaskTexturePlugin;
+
+impl Plugin for MaskTexturePlugin {
+    fn build(&self, app: &mut App) {
+        let size = Extent3d {
+            width: MASK_TEXTURE_SIZE,
+            height: MASK_TEXTURE_SIZE,
+            depth_or_array_layers: 1,
+        };
+        let mut image = Image {
+            texture_descriptor: TextureDescriptor {
+                label: Some("mask_texture"),
+                size,
+                dimension: TextureDimension::D2,
+                format: MASK_TEXTURE_FORMAT,
+                mip_level_count: 1,
+                sample_count: 1,
+                usage: TextureUsages::TEXTURE_BINDING
+                    | TextureUsages::COPY_DST
+                    | TextureUsages::RENDER_ATTACHMENT,
+            },
+            ..default()
+        };
+        // fill with zeros so the texture exists before the first pass
+        image.resize(size);
+        let image = app.world.resource_mut::<Assets<Image>>().add(image);
+
+        app.insert_resource(MaskTexture {
+            image,
+            mask: None,
+            world_min: Vec2::new(-1.0, -1.0),
+            world_max: Vec2::new(1.0, 1.0),
+        })
+        .add_plugin(ExtractResourcePlugin::<MaskTexture>::default())
+        .add_plugin(Material2dPlugin::<MaskTextureMaterial>::default())
+        .add_system(sync_mask_texture_overlays);
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<MaskLightPipeline>()
+            .init_resource::<SpecializedRenderPipelines<MaskLightPipeline>>()
+            .add_system_to_stage(RenderStage::Extract, extract_mask_lights)
+            .add_system_to_stage(RenderStage::Prepare, prepare_mask_lights);
+
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        graph.add_node(MASK_TEXTURE, MaskTextureNode);
+        graph
+            .add_node_edge(MASK_TEXTURE, main_graph::node::CAMERA_DRIVER)
+            .unwrap();
+    }
+}
+
+#[derive(Clone)]
+pub struct MaskTexture {
+    pub image: Handle<Image>,
+    // the entity whose lights are rendered, the texture stays empty while it is None
+    pub mask: Option<Entity>,
+    pub world_min: Vec2,
+    pub world_max: Vec2,
+}
+
+impl ExtractResource for MaskTexture {
+    type Source = MaskTexture;
+
+    fn extract_resource(mask_texture: &Self::Source) -> Self {
+        mask_texture.clone()
+    }
+}
+
+// Draws the overlay from the mask texture, the color is darkened where the
+// texture is lit and the light colors are added on top
+#[derive(AsBindGroup, TypeUuid, Clone)]
+#[uuid = "6c1e3f9a-4d52-4b8e-9a17-2f0d8c5b7e31"]
+#[uniform(0, MaskTextureUniform)]
+pub struct MaskTextureMaterial {
+    pub color: Color,
+    pub world_min: Vec2,
+    pub world_max: Vec2,
+    #[texture(1)]
+    #[sampler(2)]
+    pub image: Handle<Image>,
+}
+
+wgsl_struct! {
+    // The uniform of a `MaskTextureMaterial`, also declared in mask_texture_overlay.wgsl
+    #[derive(Clone, ShaderType)]
+    pub struct MaskTextureUniform {
+        color: Color,
+        world_min: Vec2,
+        world_max: Vec2,
+    }
+}
+
+impl AsBindGroupShaderType<MaskTextureUniform> for MaskTextureMaterial {
+    fn as_bind_group_shader_type(&self, _images: &RenderAssets<Image>) -> MaskTextureUniform {
+        MaskTextureUniform {
+            color: self.color,
+            world_min: self.world_min,
+            world_max: self.world_max,
+        }
+    }
+}
+
+impl MaskTextureMaterial {
+    pub fn new(color: Color, mask_texture: &MaskTexture) -> Self {
+        Self {
+            color,
+            world_min: mask_texture.world_min,
+            world_max: mask_texture.world_max,
+            image: mask_texture.image.clone(),
+        }
+    }
+}
+
+impl Material2d for MaskTextureMaterial {
+    fn fragment_shader() -> ShaderRef {
+        "mask_texture_overlay.wgsl".into()
+    }
+}
+
+// Keeps the overlay color in step with the mask input, like the uniform of
+// the per-pixel overlay
+fn sync_mask_texture_overlays(
+    mut materials: ResMut<Assets<MaskTextureMaterial>>,
+    overlay_query: Query<
+        (&CoolMaterialUniformInput, &Handle<MaskTextureMaterial>),
+        Changed<CoolMaterialUniformInput>,
+    >,
+) {
+    for (input, handle) in overlay_query.iter() {
+        let color_changed = materials
+            .get(handle)
+            .map_or(false, |material| material.color != input.color);
+        if color_changed {
+            if let Some(material) = materials.get_mut(handle) {
+                material.color = input.color;
+            }
+        }
+    }
+}
+
+// One light, the slot of CoolMaterialUniformInput it came from. Every field
+// is one vec4 attribute, the polygon takes POLYGON_VEC4S of them
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct LightInstance {
+    position: [f32; 4],
+    color: [f32; 4],
+    shape: [f32; 4],
+    orientation: [f32; 4],
+    polygon: [[f32; 4]; POLYGON_VEC4S],
+}
+
+const LIGHT_INSTANCE_ATTRIBUTES: usize = 4 + POLYGON_VEC4S;
+
+struct MaskLights {
+    key: CoolMaterialKey,
+    instances: Vec<LightInstance>,
+    programs: [ShapeProgram; MAX_SHAPE_PROGRAMS],
+}
+
+fn extract_mask_lights(
+    mut commands: Commands,
+    mask_texture: Extract<Res<MaskTexture>>,
+    materials: Extract<Res<Assets<CoolMaterial>>>,
+    mask_query: Extract<Query<(&CoolMaterialUniformInput, Option<&Handle<CoolMaterial>>)>>,
+) {
+    let (input, handle) = match mask_texture.mask.and_then(|mask| mask_query.get(mask).ok()) {
+        Some(mask) => mask,
+        None => {
+            commands.remove_resource::<MaskLights>();
+            return;
+        }
+    };
+    let instances = light_instances(input);
+    let mut key = handle
+        .and_then(|handle| materials.get(handle))
+        .map(CoolMaterialKey::from)
+        .unwrap_or_default();
+    // the shapes of the lights drawn now, the material may lag behind or be missing
+    key.shapes = LightShapes::of_slots(&input.shape[..instances.len()], &input.programs);
+    commands.insert_resource(MaskLights {
+        key,
+        instances,
+        programs: input.programs,
+    });
+}
+
+// The lights of a mask in slot order, one instance each
+fn light_instances(input: &CoolMaterialUniformInput) -> Vec<LightInstance> {
+    input
+        .lights()
+        .enumerate()
+        .map(|(slot, light)| LightInstance {
+            position: light.position.to_array(),
+            color: light.color.as_linear_rgba_f32(),
+            // as uploaded, with the program chunk of a composite
+            shape: input.shape[slot].to_array(),
+            orientation: light.orientation.to_array(),
+            polygon: light.polygon.map(|packed| packed.to_array()),
+        })
+        .collect()
+}
+
+wgsl_struct! {
+    #[derive(Clone, ShaderType)]
+    struct MaskLightUniform {
+        world_min: Vec2,
+        world_max: Vec2,
+        // compiled shape trees of the composite lights, see mask_composite.rs
+        programs: [ShapeProgram; MAX_SHAPE_PROGRAMS],
+    }
+}
+
+struct PreparedMaskLights {
+    pipeline: CachedRenderPipelineId,
+    bind_group: BindGroup,
+    instances: Buffer,
+    count: u32,
+}
+
+fn prepare_mask_lights(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    light_pipeline: Res<MaskLightPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<MaskLightPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    mask_texture: Res<MaskTexture>,
+    lights: Option<Res<MaskLights>>,
+) {
+    let lights = match lights {
+        Some(lights) if !lights.instances.is_empty() => lights,
+        _ => {
+            commands.remove_resource::<PreparedMaskLights>();
+            return;
+        }
+    };
+    let pipeline = pipelines.specialize(&mut pipeline_cache, &light_pipeline, lights.key);
+
+    let mut uniform = encase::UniformBuffer::new(Vec::new());
+    uniform
+        .write(&MaskLightUniform {
+            world_min: mask_texture.world_min,
+            world_max: mask_texture.world_max,
+            programs: lights.programs,
+        })
+        .unwrap();
+    let uniform = render_device.create_buffer_with_data(&BufferInitDescriptor {
+        label: Some("mask_light_uniform_buffer"),
+        contents: uniform.as_ref(),
+        usage: BufferUsages::UNIFORM,
+    });
+    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("mask_light_bind_group"),
+        layout: &light_pipeline.layout,
+        entries: &[BindGroupEntry {
+            binding: 0,
+            resource: uniform.as_entire_binding(),
+        }],
+    });
+    let instances = render_device.create_buffer_with_data(&BufferInitDescriptor {
+        label: Some("mask_light_instance_buffer"),
+        contents: bytemuck::cast_slice(lights.instances.as_slice()),
+        usage: BufferUsages::VERTEX,
+    });
+
+    commands.insert_resource(PreparedMaskLights {
+        pipeline,
+        bind_group,
+        instances,
+        count: lights.instances.len() as u32,
+    });
+}
+
+pub struct MaskLightPipeline {
+    shader: Handle<Shader>,
+    layout: BindGroupLayout,
+}
+
+impl FromWorld for MaskLightPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let shader = world.resource::<AssetServer>().load("mask_lights.wgsl");
+        let layout =
+            world
+                .resource::<RenderDevice>()
+                .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    label: Some("mask_light_layout"),
+                    entries: &[BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::VERTEX,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: Some(MaskLightUniform::min_size()),
+                        },
+                        count: None,
+                    }],
+                });
+        Self { shader, layout }
+    }
+}
+
+// Every field of `LightInstance` in order, from @location(0) of the Vertex in
+// mask_lights.wgsl on
+fn light_instance_attributes() -> Vec<VertexAttribute> {
+    (0..LIGHT_INSTANCE_ATTRIBUTES as u32)
+        .map(|location| VertexAttribute {
+            format: VertexFormat::Float32x4,
+            offset: location as u64 * VertexFormat::Float32x4.size(),
+            shader_location: location,
+        })
+        .collect()
+}
+
+impl SpecializedRenderPipeline for MaskLightPipeline {
+    type Key = CoolMaterialKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let blend = match key.blend {
+            // lit' = lit + dst * (1 - lit), the complement of multiplying the coverages
+            MaskBlend::Multiply => BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+            MaskBlend::Min => {
+                let max = BlendComponent {
+                    src_factor: BlendFactor::One,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Max,
+                };
+                BlendState {
+                    color: max,
+                    alpha: max,
+                }
+            }
+        };
+
+        RenderPipelineDescriptor {
+            label: Some("mask_light_pipeline".into()),
+            layout: Some(vec![self.layout.clone()]),
+            vertex: VertexState {
+                shader: self.shader.clone(),
+                shader_defs: Vec::new(),
+                entry_point: "vertex".into(),
+                buffers: vec![VertexBufferLayout {
+                    array_stride: std::mem::size_of::<LightInstance>() as u64,
+                    step_mode: VertexStepMode::Instance,
+                    attributes: light_instance_attributes(),
+                }],
+            },
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs: key.shader_defs(),
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: MASK_TEXTURE_FORMAT,
+                    blend: Some(blend),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+        }
+    }
+}
+
+struct MaskTextureNode;
+
+impl Node for MaskTextureNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let mask_texture = world.resource::<MaskTexture>();
+        let target = match world
+            .resource::<RenderAssets<Image>>()
+            .get(&mask_texture.image)
+        {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+
+        // cleared even without lights, so a removed light does not linger
+        let mut pass = render_context
+            .command_encoder
+            .begin_render_pass(&RenderPassDescriptor {
+                label: Some("mask_texture_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &target.texture_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::NONE.into()),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+
+        let lights = match world.get_resource::<PreparedMaskLights>() {
+            Some(lights) => lights,
+            None => return Ok(()),
+        };
+        let pipeline = match world
+            .resource::<PipelineCache>()
+            .get_render_pipeline(lights.pipeline)
+        {
+            Some(pipeline) => pipeline,
+            None => return Ok(()),
+        };
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &lights.bind_group, &[]);
+        pass.set_vertex_buffer(0, lights.instances.slice(..));
+        // two triangles per light
+        pass.draw(0..6, 0..lights.count);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mask_composite::{ShapeTree, EMPTY_SHAPE_PROGRAM},
+        mask_shape::{polygon_vertex, LightShape, SHAPE_COMPOSITE, SHAPE_POLYGON},
+        shader_layout::{
+            assert_generated_wgsl, assert_uniform_matches, assert_uniform_matches_at,
+            wgsl_struct_locations,
+        },
+    };
+    use bevy::utils::HashMap;
+
+    #[test]
+    fn light_generated_wgsl_is_up_to_date() {
+        assert_generated_wgsl::<MaskLightUniform>("generated/mask_light_uniform.wgsl");
+    }
+
+    #[test]
+    fn overlay_generated_wgsl_is_up_to_date() {
+        assert_generated_wgsl::<MaskTextureUniform>("generated/mask_texture_uniform.wgsl");
+    }
+
+    #[test]
+    fn overlay_uniform_layout_matches_shader() {
+        assert_uniform_matches::<MaskTextureUniform, 3>("mask_texture_overlay.wgsl", 0, &[]);
+    }
+
+    #[test]
+    fn light_uniform_layout_matches_shader() {
+        assert_uniform_matches_at::<MaskLightUniform, 3>(
+            "mask_lights.wgsl",
+            0,
+            0,
+            &CoolMaterialKey::default().shader_defs(),
+        );
+    }
+
+    #[test]
+    fn instance_layout_matches_shader() {
+        let instance = LightInstance::zeroed();
+        let base = &instance as *const LightInstance as usize;
+        let mut field_offsets = vec![
+            &instance.position as *const [f32; 4] as usize - base,
+            &instance.color as *const [f32; 4] as usize - base,
+            &instance.shape as *const [f32; 4] as usize - base,
+            &instance.orientation as *const [f32; 4] as usize - base,
+        ];
+        field_offsets.extend(
+            instance
+                .polygon
+                .iter()
+                .map(|packed| packed as *const [f32; 4] as usize - base),
+        );
+        assert_eq!(
+            std::mem::size_of::<LightInstance>(),
+            LIGHT_INSTANCE_ATTRIBUTES * 16
+        );
+
+        let inputs: HashMap<u32, u64> = wgsl_struct_locations("mask_lights.wgsl", "Vertex")
+            .into_iter()
+            .collect();
+        let attributes = light_instance_attributes();
+        assert_eq!(attributes.len(), field_offsets.len());
+        assert_eq!(inputs.len(), attributes.len());
+        for (attribute, field_offset) in attributes.iter().zip(field_offsets) {
+            assert_eq!(attribute.offset, field_offset as u64);
+            assert_eq!(
+                inputs.get(&attribute.shader_location),
+                Some(&attribute.format.size()),
+                "@location({}) of mask_lights.wgsl",
+                attribute.shader_location
+            );
+        }
+    }
+
+    #[test]
+    fn lights_are_packed_into_instances_in_slot_order() {
+        let mut input = CoolMaterialUniformInput::default();
+        let triangle = LightShape::Polygon {
+            vertices: vec![
+                Vec2::new(0.0, 0.1),
+                Vec2::new(-0.1, -0.1),
+                Vec2::new(0.1, -0.1),
+            ],
+        };
+        let tree = ShapeTree::shape(LightShape::Circle).at(Vec2::new(0.1, 0.0));
+        assert!(input.push_light(Vec4::new(0.5, 0.0, 0.04, 1.0), Color::RED));
+        for shape in [triangle, LightShape::Composite(tree)] {
+            let slot = shape.to_slot(Mat4::IDENTITY, 0.02, Color::WHITE);
+            assert!(input.push_slot(&slot));
+        }
+
+        let instances = light_instances(&input);
+        assert_eq!(instances.len(), 3);
+        let circle = &instances[0];
+        assert_eq!(circle.position, [0.5, 0.0, 0.04, 1.0]);
+        assert_eq!(circle.color, Color::RED.as_linear_rgba_f32());
+
+        let polygon = &instances[1];
+        assert_eq!(polygon.shape[3] as u32, SHAPE_POLYGON);
+        let packed = polygon.polygon.map(Vec4::from_array);
+        assert_eq!(polygon_vertex(&packed, 2), Vec2::new(0.1, -0.1));
+
+        // a composite carries the chunk its program went to
+        let composite = &instances[2];
+        assert_eq!(composite.shape, input.shape[2].to_array());
+        assert_eq!(composite.shape[3] as u32, SHAPE_COMPOSITE);
+        let chunk = composite.shape[1] as usize;
+        assert_ne!(input.programs[chunk], EMPTY_SHAPE_PROGRAM);
+    }
+}
@@ -0,0 +1,338 @@
+// Bookkeeping for `prepare_my_material` so the mask uniform is only uploaded
+// when it changed, and then only the parts that changed.
+//
+// The render world keeps the last input written to every material's buffer.
+// A frame with no change uploads nothing, a moved light uploads its 16 byte
+// position slot. The encoded struct goes through one scratch buffer that is
+// reused every frame. The number of bytes sent is reported as a diagnostic.
+
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics, LogDiagnosticsPlugin},
+    prelude::*,
+    render::{
+        render_resource::{encase, BufferId, ShaderType},
+        RenderApp,
+    },
+    utils::HashMap,
+};
+
+use crate::{
+    mask_composite::SHAPE_PROGRAM_VEC4S, mask_shape::POLYGON_VEC4S, CoolMaterial,
+    CoolMaterialUniformBuffer, CoolMaterialUniformInput,
+};
+
+pub const MASK_UPLOAD_BYTES: DiagnosticId =
+    DiagnosticId::from_u128(78412075343496128887334127829035402900);
+
+// Stride of one light slot in the uniform arrays, vec4<f32>
+const SLOT_SIZE: u64 = 16;
+// Stride of one light's polygon, array<vec4<f32>, POLYGON_VEC4S>
+const POLYGON_SLOT_SIZE: u64 = SLOT_SIZE * POLYGON_VEC4S as u64;
+// Stride of one program chunk, array<vec4<f32>, SHAPE_PROGRAM_VEC4S>
+const PROGRAM_SLOT_SIZE: u64 = SLOT_SIZE * SHAPE_PROGRAM_VEC4S as u64;
+// light_count, u32
+const LIGHT_COUNT_SIZE: u64 = 4;
+
+pub struct MaskUploadPlugin;
+
+impl Plugin for MaskUploadPlugin {
+    fn build(&self, app: &mut App) {
+        let uploaded_bytes = MaskUploadBytes::default();
+        app.insert_resource(uploaded_bytes.clone())
+            .add_plugin(LogDiagnosticsPlugin {
+                filter: Some(vec![MASK_UPLOAD_BYTES]),
+                ..default()
+            })
+            .add_startup_system(register_mask_upload_diagnostic)
+            .add_system(report_mask_upload_bytes);
+        app.sub_app_mut(RenderApp)
+            .insert_resource(uploaded_bytes)
+            .init_resource::<MaskUploadCache>();
+    }
+}
+
+// Shared by both worlds, the render world adds to it and the main world
+// drains it into the diagnostic once per frame
+#[derive(Clone, Default)]
+pub struct MaskUploadBytes(Arc<AtomicU64>);
+
+impl MaskUploadBytes {
+    pub fn add(&self, bytes: u64) {
+        self.0.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+struct UploadedMask {
+    // a re-prepared material gets a fresh buffer that needs a full upload
+    buffer: BufferId,
+    input: CoolMaterialUniformInput,
+}
+
+pub struct MaskUploadCache {
+    pub scratch: encase::UniformBuffer<Vec<u8>>,
+    uploaded: HashMap<Handle<CoolMaterial>, UploadedMask>,
+}
+
+impl Default for MaskUploadCache {
+    fn default() -> Self {
+        Self {
+            scratch: encase::UniformBuffer::new(Vec::new()),
+            uploaded: HashMap::default(),
+        }
+    }
+}
+
+impl MaskUploadCache {
+    pub fn needs_upload(
+        &self,
+        handle: &Handle<CoolMaterial>,
+        buffer: BufferId,
+        input: &CoolMaterialUniformInput,
+    ) -> bool {
+        match self.uploaded.get(handle) {
+            Some(previous) => previous.buffer != buffer || !same_upload(&previous.input, input),
+            None => true,
+        }
+    }
+
+    // Calls `upload` with every byte range of the buffer that differs from the
+    // last upload, nothing when the input did not change
+    pub fn for_each_dirty_range(
+        &self,
+        handle: &Handle<CoolMaterial>,
+        buffer: BufferId,
+        input: &CoolMaterialUniformInput,
+        mut upload: impl FnMut(Range<u64>),
+    ) {
+        match self.uploaded.get(handle) {
+            Some(previous) if previous.buffer == buffer => {
+                dirty_ranges(&previous.input, input, &mut upload)
+            }
+            _ => upload(0..CoolMaterialUniformBuffer::min_size().get()),
+        }
+    }
+
+    pub fn mark_uploaded(
+        &mut self,
+        handle: &Handle<CoolMaterial>,
+        buffer: BufferId,
+        input: &CoolMaterialUniformInput,
+    ) {
+        self.uploaded.insert(
+            handle.clone_weak(),
+            UploadedMask {
+                buffer,
+                input: *input,
+            },
+        );
+    }
+
+    // Forget materials that are no longer prepared
+    pub fn retain(&mut self, mut keep: impl FnMut(&Handle<CoolMaterial>) -> bool) {
+        self.uploaded.retain(|handle, _| keep(handle));
+    }
+}
+
+// Whether both inputs write the same uniform. The fades and the count of
+// source lights only live on the CPU, the uniform has the lights they made
+fn same_upload(previous: &CoolMaterialUniformInput, input: &CoolMaterialUniformInput) -> bool {
+    previous.color == input.color
+        && previous.position == input.position
+        && previous.light_color == input.light_color
+        && previous.light_count == input.light_count
+        && previous.shape == input.shape
+        && previous.orientation == input.orientation
+        && previous.polygon == input.polygon
+        && previous.programs == input.programs
+}
+
+// The byte ranges of the uniform where `input` differs from `previous`
+fn dirty_ranges(
+    previous: &CoolMaterialUniformInput,
+    input: &CoolMaterialUniformInput,
+    upload: &mut impl FnMut(Range<u64>),
+) {
+    if same_upload(previous, input) {
+        return;
+    }
+
+    let metadata = CoolMaterialUniformBuffer::METADATA;
+    if previous.color != input.color {
+        upload(metadata.offset(0)..metadata.offset(0) + SLOT_SIZE);
+    }
+    dirty_slots(
+        &previous.position,
+        &input.position,
+        metadata.offset(1),
+        SLOT_SIZE,
+        upload,
+    );
+    dirty_slots(
+        &previous.light_color,
+        &input.light_color,
+        metadata.offset(2),
+        SLOT_SIZE,
+        upload,
+    );
+    if previous.light_count != input.light_count {
+        upload(metadata.offset(3)..metadata.offset(3) + LIGHT_COUNT_SIZE);
+    }
+    dirty_slots(
+        &previous.shape,
+        &input.shape,
+        metadata.offset(4),
+        SLOT_SIZE,
+        upload,
+    );
+    dirty_slots(
+        &previous.orientation,
+        &input.orientation,
+        metadata.offset(5),
+        SLOT_SIZE,
+        upload,
+    );
+    dirty_slots(
+        &previous.polygon,
+        &input.polygon,
+        metadata.offset(6),
+        POLYGON_SLOT_SIZE,
+        upload,
+    );
+    dirty_slots(
+        &previous.programs,
+        &input.programs,
+        metadata.offset(7),
+        PROGRAM_SLOT_SIZE,
+        upload,
+    );
+}
+
+// Consecutive changed slots are merged into one range
+fn dirty_slots<T: PartialEq>(
+    previous: &[T],
+    current: &[T],
+    offset: u64,
+    stride: u64,
+    upload: &mut impl FnMut(Range<u64>),
+) {
+    let mut run_start = None;
+    for slot in 0..=current.len() {
+        let dirty = slot < current.len() && previous[slot] != current[slot];
+        match (dirty, run_start) {
+            (true, None) => run_start = Some(slot),
+            (false, Some(start)) => {
+                upload(offset + start as u64 * stride..offset + slot as u64 * stride);
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn register_mask_upload_diagnostic(mut diagnostics: ResMut<Diagnostics>) {
+    diagnostics.add(Diagnostic::new(MASK_UPLOAD_BYTES, "mask_upload_bytes", 20));
+}
+
+fn report_mask_upload_bytes(
+    uploaded_bytes: Res<MaskUploadBytes>,
+    mut diagnostics: ResMut<Diagnostics>,
+) {
+    let bytes = uploaded_bytes.0.swap(0, Ordering::Relaxed);
+    diagnostics.add_measurement(MASK_UPLOAD_BYTES, bytes as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mask_fade::{Easing, LightFade, SlotFade};
+    use bevy::asset::HandleId;
+
+    fn ranges(
+        previous: &CoolMaterialUniformInput,
+        input: &CoolMaterialUniformInput,
+    ) -> Vec<Range<u64>> {
+        let mut ranges = Vec::new();
+        dirty_ranges(previous, input, &mut |range| ranges.push(range));
+        ranges
+    }
+
+    #[test]
+    fn no_change_uploads_nothing() {
+        let mut input = CoolMaterialUniformInput::default();
+        input.push_light(Vec4::new(0.1, 0.0, 0.1, 1.0), Color::WHITE);
+        assert_eq!(ranges(&input, &input), vec![]);
+    }
+
+    #[test]
+    fn changed_fades_alone_upload_nothing() {
+        let mut previous = CoolMaterialUniformInput::default();
+        previous.push_light(Vec4::new(0.1, 0.0, 0.1, 1.0), Color::WHITE);
+        let mut input = previous;
+        input.fades[0] = Some(SlotFade {
+            light: previous.slot(0),
+            fade: LightFade::fade_out(0.5, Easing::Linear),
+        });
+        assert!(input != previous);
+
+        let mut cache = MaskUploadCache::default();
+        let handle = Handle::weak(HandleId::random::<CoolMaterial>());
+        let buffer = BufferId::new();
+        cache.mark_uploaded(&handle, buffer, &previous);
+        assert!(!cache.needs_upload(&handle, buffer, &input));
+        assert_eq!(ranges(&previous, &input), vec![]);
+
+        input.position[0].x += 1.0;
+        assert!(cache.needs_upload(&handle, buffer, &input));
+    }
+
+    #[test]
+    fn neighbouring_slots_merge_into_one_range() {
+        let mut slots = vec![];
+        let previous = [0, 0, 0, 0, 0, 0];
+        dirty_slots(&previous, &[1, 1, 0, 1, 0, 1], 100, 16, &mut |range| {
+            slots.push(range)
+        });
+        assert_eq!(slots, vec![100..132, 148..164, 180..196]);
+    }
+
+    #[test]
+    fn moved_lights_upload_their_position_slots() {
+        let mut previous = CoolMaterialUniformInput::default();
+        for x in [0.1, 0.2, 0.3, 0.4] {
+            previous.push_light(Vec4::new(x, 0.0, 0.1, 1.0), Color::WHITE);
+        }
+        let mut input = previous;
+        input.position[0].x += 1.0;
+        input.position[1].x += 1.0;
+        input.position[3].x += 1.0;
+        let positions = CoolMaterialUniformBuffer::METADATA.offset(1);
+        assert_eq!(
+            ranges(&previous, &input),
+            vec![positions..positions + 32, positions + 48..positions + 64]
+        );
+    }
+
+    #[test]
+    fn added_lights_upload_their_slots_and_the_count() {
+        let previous = CoolMaterialUniformInput::default();
+        let mut input = previous;
+        input.push_light(Vec4::new(0.1, 0.0, 0.1, 1.0), Color::WHITE);
+        let metadata = CoolMaterialUniformBuffer::METADATA;
+        assert_eq!(
+            ranges(&previous, &input),
+            vec![
+                metadata.offset(1)..metadata.offset(1) + 16,
+                metadata.offset(2)..metadata.offset(2) + 16,
+                metadata.offset(3)..metadata.offset(3) + 4,
+            ]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+// Light exposure for gameplay entities, from the same math the overlay draws.
+//
+// Any entity with a `MaskVisibility` gets its exposure refreshed every frame
+// after transforms are propagated. `EnteredLight` / `LeftLight` fire when the
+// exposure crosses the thresholds in `MaskVisibilitySettings`; the gap between
+// the two keeps an entity standing on a soft edge from flickering.
+
+use bevy::{ecs::system::SystemParam, prelude::*, transform::TransformSystem};
+
+use crate::{
+    mask_post_process::ScreenMask,
+    mask_sample::{in_overlay, sample_light, sample_lights, sample_mask},
+    mask_source::{LightBeam, LightSource, LightSourceSlots, LightSourceSystem},
+    CoolMaterial, CoolMaterialKey, CoolMaterialUniformInput,
+};
+
+pub struct MaskVisibilityPlugin;
+
+impl Plugin for MaskVisibilityPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MaskVisibilitySettings>()
+            .add_event::<EnteredLight>()
+            .add_event::<LeftLight>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                update_mask_visibility
+                    .after(TransformSystem::TransformPropagate)
+                    .after(LightSourceSystem),
+            );
+    }
+}
+
+pub struct MaskVisibilitySettings {
+    // exposure needed to count as lit
+    pub enter_threshold: f32,
+    // exposure below which a lit entity counts as dark again
+    pub leave_threshold: f32,
+}
+
+impl Default for MaskVisibilitySettings {
+    fn default() -> Self {
+        Self {
+            enter_threshold: 0.6,
+            leave_threshold: 0.4,
+        }
+    }
+}
+
+#[derive(Component, Clone, Copy, Default, Debug)]
+pub struct MaskVisibility {
+    // 0 is fully masked, 1 fully lit
+    pub exposure: f32,
+    pub lit: bool,
+}
+
+pub struct EnteredLight {
+    pub entity: Entity,
+}
+
+pub struct LeftLight {
+    pub entity: Entity,
+}
+
+// Exposure to a single `LightSource` or `LightBeam` rather than the whole
+// mask, like whether the player stands in a guard's vision cone. It samples
+// the light the source got in its mask with the mask's falloff, so a source
+// that did not fit into the mask, or a point outside the overlay, is dark
+#[derive(SystemParam)]
+pub struct LightSourceExposure<'w, 's> {
+    source_slots: Res<'w, LightSourceSlots>,
+    materials: Res<'w, Assets<CoolMaterial>>,
+    source_query: Query<'w, 's, AnyOf<(&'static LightSource, &'static LightBeam)>>,
+    mask_query: Query<
+        'w,
+        's,
+        (
+            &'static CoolMaterialUniformInput,
+            Option<&'static Handle<CoolMaterial>>,
+            &'static GlobalTransform,
+            Option<&'static ScreenMask>,
+        ),
+    >,
+}
+
+impl<'w, 's> LightSourceExposure<'w, 's> {
+    // 0 is dark, 1 fully in the light of `source`
+    pub fn of(&self, source: Entity, world_pos: Vec2) -> f32 {
+        let light = self
+            .source_query
+            .get(source)
+            .ok()
+            .and_then(|(light_source, beam)| {
+                let mask = light_source
+                    .map(|light_source| light_source.mask)
+                    .or_else(|| beam.map(|beam| beam.mask))?;
+                let (input, handle, overlay_transform, screen_mask) =
+                    self.mask_query.get(mask).ok()?;
+                if screen_mask.is_none() && !in_overlay(overlay_transform, world_pos) {
+                    return None;
+                }
+                let light = input.source_light(self.source_slots.get(source)?)?;
+                Some((light, mask_key(&self.materials, handle)))
+            });
+        match light {
+            Some((light, key)) => sample_light(&light, &key, world_pos),
+            None => 0.0,
+        }
+    }
+}
+
+// Overlays drawn from the mask texture have no CoolMaterial
+fn mask_key(
+    materials: &Assets<CoolMaterial>,
+    handle: Option<&Handle<CoolMaterial>>,
+) -> CoolMaterialKey {
+    handle
+        .and_then(|handle| materials.get(handle))
+        .map(CoolMaterialKey::from)
+        .unwrap_or_default()
+}
+
+fn update_mask_visibility(
+    settings: Res<MaskVisibilitySettings>,
+    materials: Res<Assets<CoolMaterial>>,
+    overlay_query: Query<(
+        &CoolMaterialUniformInput,
+        Option<&Handle<CoolMaterial>>,
+        &GlobalTransform,
+        Option<&ScreenMask>,
+    )>,
+    mut visibility_query: Query<(Entity, &GlobalTransform, &mut MaskVisibility)>,
+    mut entered_events: EventWriter<EnteredLight>,
+    mut left_events: EventWriter<LeftLight>,
+) {
+    for (entity, transform, mut visibility) in visibility_query.iter_mut() {
+        let world_pos = transform.translation().truncate();
+        // overlapping overlays each darken the point, the darkest one wins
+        let exposure = overlay_query
+            .iter()
+            .map(|(colordata, handle, overlay_transform, screen_mask)| {
+                let key = mask_key(&materials, handle);
+                match screen_mask {
+                    Some(_) => sample_lights(colordata, &key, world_pos),
+                    None => sample_mask(colordata, &key, overlay_transform, world_pos),
+                }
+            })
+            .fold(1.0, f32::min);
+
+        let lit = if visibility.lit {
+            exposure >= settings.leave_threshold
+        } else {
+            exposure >= settings.enter_threshold
+        };
+
+        if lit && !visibility.lit {
+            entered_events.send(EnteredLight { entity });
+        } else if !lit && visibility.lit {
+            left_events.send(LeftLight { entity });
+        }
+
+        // only touch the component when something changed, so Changed<MaskVisibility> stays useful
+        if visibility.exposure != exposure || visibility.lit != lit {
+            visibility.exposure = exposure;
+            visibility.lit = lit;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::{
+        asset::AssetPlugin,
+        ecs::event::{Events, ManualEventReader},
+    };
+
+    // radius 0.2 and a soft band of half that
+    const LIGHT: Vec4 = Vec4::new(0.0, 0.0, 0.16, 0.5);
+
+    fn exposure_at(input: &CoolMaterialUniformInput, x: f32) -> f32 {
+        sample_mask(
+            input,
+            &CoolMaterialKey::default(),
+            &GlobalTransform::default(),
+            Vec2::new(x, 0.0),
+        )
+    }
+
+    // Where the exposure falls to `exposure` on the way out of the light
+    fn x_with_exposure(input: &CoolMaterialUniformInput, exposure: f32) -> f32 {
+        let (mut near, mut far) = (0.0, 0.45);
+        for _ in 0..32 {
+            let mid = (near + far) * 0.5;
+            if exposure_at(input, mid) > exposure {
+                near = mid;
+            } else {
+                far = mid;
+            }
+        }
+        near
+    }
+
+    #[test]
+    fn crossings_fire_once_and_the_band_holds() {
+        let mut app = App::default();
+        app.add_plugin(TransformPlugin)
+            .add_plugin(AssetPlugin)
+            .add_asset::<CoolMaterial>()
+            .add_plugin(MaskVisibilityPlugin);
+        let mut input = CoolMaterialUniformInput::default();
+        assert!(input.push_light(LIGHT, Color::WHITE));
+        let (dark, center) = (0.45, 0.0);
+        let band = [0.45, 0.5, 0.55].map(|exposure| x_with_exposure(&input, exposure));
+        for (x, expected) in band.iter().zip([0.45, 0.5, 0.55]) {
+            assert!((exposure_at(&input, *x) - expected).abs() < 1e-3);
+        }
+        app.world
+            .spawn()
+            .insert_bundle(TransformBundle::default())
+            .insert(input);
+        let entity = app
+            .world
+            .spawn()
+            .insert_bundle(TransformBundle::from_transform(Transform::from_xyz(
+                dark, 0.0, 0.0,
+            )))
+            .insert(MaskVisibility::default())
+            .id();
+
+        let mut entered_reader = ManualEventReader::<EnteredLight>::default();
+        let mut left_reader = ManualEventReader::<LeftLight>::default();
+        // (x, entered, left, lit) after moving there
+        let path = [
+            (dark, 0, 0, false),
+            (band[0], 0, 0, false),
+            (band[1], 0, 0, false),
+            (band[2], 0, 0, false),
+            (center, 1, 0, true),
+            (band[2], 0, 0, true),
+            (band[1], 0, 0, true),
+            (band[0], 0, 0, true),
+            (dark, 0, 1, false),
+            (band[1], 0, 0, false),
+            (center, 1, 0, true),
+            (dark, 0, 1, false),
+        ];
+        for (step, (x, entered, left, lit)) in path.into_iter().enumerate() {
+            app.world
+                .get_mut::<Transform>(entity)
+                .unwrap()
+                .translation
+                .x = x;
+            app.update();
+            let entered_events = app.world.resource::<Events<EnteredLight>>();
+            let left_events = app.world.resource::<Events<LeftLight>>();
+            assert_eq!(
+                (
+                    entered_reader.iter(entered_events).count(),
+                    left_reader.iter(left_events).count()
+                ),
+                (entered, left),
+                "events after step {} to x {}",
+                step,
+                x
+            );
+            assert_eq!(app.world.get::<MaskVisibility>(entity).unwrap().lit, lit);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// Typed access to the GPU resources of a prepared `Material2d`.
+//
+// `AsBindGroup` stores one `OwnedBindingResource` per binding, but not in
+// binding order: the derive puts every `#[texture]` and `#[sampler]` first, in
+// field order, and the merged `#[uniform]` buffers after them, sorted by
+// binding number. So a textured material with `#[uniform(0)]`, `#[texture(1)]`
+// and `#[sampler(2)]` has its uniform at `bindings[2]`. The derive doesn't say
+// which bindings are uniforms, `UniformBindings` lists them.
+
+use std::fmt;
+
+use bevy::{
+    prelude::*,
+    render::render_resource::{Buffer, OwnedBindingResource},
+    sprite::{Material2d, PreparedMaterial2d},
+    utils::HashSet,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BindingError {
+    NotAUniform { binding: u32 },
+    Missing { binding: u32, count: usize },
+    NotABuffer { binding: u32, found: &'static str },
+}
+
+impl fmt::Display for BindingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindingError::NotAUniform { binding } => {
+                write!(f, "binding {} is not in UNIFORM_BINDINGS", binding)
+            }
+            BindingError::Missing { binding, count } => write!(
+                f,
+                "binding {} does not exist, the material only has {} bindings",
+                binding, count
+            ),
+            BindingError::NotABuffer { binding, found } => write!(
+                f,
+                "binding {} is a {}, not a #[uniform] buffer, UNIFORM_BINDINGS is wrong",
+                binding, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BindingError {}
+
+// The `#[uniform(n)]` binding numbers of a material, every material here only
+// has `#[uniform(0)]`
+pub trait UniformBindings {
+    const UNIFORM_BINDINGS: &'static [u32] = &[0];
+}
+
+// Where `#[uniform(binding)]` sits among the `count` bindings of `M`
+pub fn uniform_binding_index<M: UniformBindings>(
+    binding: u32,
+    count: usize,
+) -> Result<usize, BindingError> {
+    let uniforms = M::UNIFORM_BINDINGS;
+    if !uniforms.contains(&binding) {
+        return Err(BindingError::NotAUniform { binding });
+    }
+    // the buffers come last, ordered by binding number
+    let first_buffer = count
+        .checked_sub(uniforms.len())
+        .ok_or(BindingError::Missing { binding, count })?;
+    let rank = uniforms.iter().filter(|&&other| other < binding).count();
+    Ok(first_buffer + rank)
+}
+
+// The buffer behind the `#[uniform(binding)]` fields of `material`
+pub fn uniform_buffer<M: Material2d + UniformBindings>(
+    material: &PreparedMaterial2d<M>,
+    binding: u32,
+) -> Result<&Buffer, BindingError> {
+    let index = uniform_binding_index::<M>(binding, material.bindings.len())?;
+    match &material.bindings[index] {
+        OwnedBindingResource::Buffer(buffer) => Ok(buffer),
+        OwnedBindingResource::TextureView(_) => Err(BindingError::NotABuffer {
+            binding,
+            found: "texture",
+        }),
+        OwnedBindingResource::Sampler(_) => Err(BindingError::NotABuffer {
+            binding,
+            found: "sampler",
+        }),
+    }
+}
+
+// Remembers which materials already reported a binding error, so a broken
+// material logs once instead of every frame
+pub struct BindingErrorLog<M: Material2d> {
+    reported: HashSet<Handle<M>>,
+}
+
+impl<M: Material2d> Default for BindingErrorLog<M> {
+    fn default() -> Self {
+        Self {
+            reported: HashSet::default(),
+        }
+    }
+}
+
+impl<M: Material2d> BindingErrorLog<M> {
+    pub fn report(&mut self, handle: &Handle<M>, err: &BindingError) {
+        if self.reported.insert(handle.clone_weak()) {
+            error!(
+                "can't upload uniforms of {} {:?}: {}",
+                std::any::type_name::<M>(),
+                handle.id,
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::{
+        reflect::TypeUuid,
+        render::render_resource::{AsBindGroup, ShaderRef},
+    };
+
+    // Laid out like the sprite materials of main_b and main_w
+    #[derive(AsBindGroup, TypeUuid, Clone)]
+    #[uuid = "3c9e1b27-5a84-4f0d-8e62-b17d4a0f9c53"]
+    struct TexturedMaterial {
+        #[uniform(0)]
+        color: Color,
+        #[texture(1)]
+        #[sampler(2)]
+        image: Handle<Image>,
+    }
+
+    impl Material2d for TexturedMaterial {
+        fn fragment_shader() -> ShaderRef {
+            "my_material_b.wgsl".into()
+        }
+    }
+
+    impl UniformBindings for TexturedMaterial {}
+
+    // uniforms on both sides of the texture
+    struct SplitMaterial;
+
+    impl UniformBindings for SplitMaterial {
+        const UNIFORM_BINDINGS: &'static [u32] = &[0, 3];
+    }
+
+    struct UniformOnlyMaterial;
+
+    impl UniformBindings for UniformOnlyMaterial {}
+
+    #[test]
+    fn uniforms_come_after_textures_and_samplers() {
+        assert_eq!(uniform_binding_index::<TexturedMaterial>(0, 3), Ok(2));
+        assert_eq!(uniform_binding_index::<UniformOnlyMaterial>(0, 1), Ok(0));
+        assert_eq!(uniform_binding_index::<SplitMaterial>(0, 4), Ok(2));
+        assert_eq!(uniform_binding_index::<SplitMaterial>(3, 4), Ok(3));
+    }
+
+    #[test]
+    fn textures_and_missing_bindings_are_errors() {
+        assert_eq!(
+            uniform_binding_index::<TexturedMaterial>(1, 3),
+            Err(BindingError::NotAUniform { binding: 1 })
+        );
+        assert_eq!(
+            uniform_binding_index::<SplitMaterial>(3, 1),
+            Err(BindingError::Missing {
+                binding: 3,
+                count: 1
+            })
+        );
+    }
+}
@@ -0,0 +1,232 @@
+// Test helpers that compare the uniform structs declared in the .wgsl assets,
+// the materials' at `@group(1)`, with the encase layout of the Rust structs we
+// write into them.
+//
+// Every binary checks its uniform types against its shader, so a drift fails
+// `cargo test` instead of showing up as garbage on screen. The structs
+// themselves come from assets/generated, see wgsl_struct.rs.
+
+use std::{env, fmt, fs};
+
+use bevy::{
+    asset::HandleId,
+    prelude::Handle,
+    render::render_resource::{
+        encase::private::{ShaderType, StructMetadata},
+        Shader, ShaderImport, ShaderProcessor,
+    },
+    utils::HashMap,
+};
+
+use crate::wgsl_struct::WgslStruct;
+
+#[derive(PartialEq, Eq)]
+pub struct StructLayout {
+    pub size: u64,
+    pub offsets: Vec<u64>,
+}
+
+impl fmt::Debug for StructLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "size {} with field offsets {:?}",
+            self.size, self.offsets
+        )
+    }
+}
+
+fn asset_path(path: &str) -> String {
+    format!("{}/assets/{}", env!("CARGO_MANIFEST_DIR"), path)
+}
+
+fn load_shader(path: &str) -> Shader {
+    let path = asset_path(path);
+    let source =
+        fs::read_to_string(&path).unwrap_or_else(|err| panic!("can't read {}: {}", path, err));
+    Shader::from_wgsl(source)
+}
+
+// Imports resolved from the assets folder: `#import "path"` by file, like the
+// asset server does, and `#import name` by the `#define_import_path` of a file
+fn load_imports(
+    shader: &Shader,
+    shaders: &mut HashMap<Handle<Shader>, Shader>,
+    import_handles: &mut HashMap<ShaderImport, Handle<Shader>>,
+) {
+    for import in shader.imports() {
+        if import_handles.contains_key(import) {
+            continue;
+        }
+        let imported = match import {
+            ShaderImport::AssetPath(path) => load_shader(path),
+            ShaderImport::Custom(_) => find_import_path(import),
+        };
+        let handle = Handle::weak(HandleId::random::<Shader>());
+        import_handles.insert(import.clone(), handle.clone());
+        load_imports(&imported, shaders, import_handles);
+        shaders.insert(handle, imported);
+    }
+}
+
+fn find_import_path(import: &ShaderImport) -> Shader {
+    let dir = asset_path("");
+    fs::read_dir(&dir)
+        .unwrap_or_else(|err| panic!("can't list {}: {}", dir, err))
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".wgsl"))
+        .map(|name| load_shader(&name))
+        .find(|shader| shader.import_path() == Some(import))
+        .unwrap_or_else(|| panic!("no shader in {} defines {:?}", dir, import))
+}
+
+// Layout of the struct bound at `@group(group) @binding(binding)` in an asset
+// shader, after running the same preprocessor the renderer does with `shader_defs`
+pub fn wgsl_uniform_layout(
+    shader: &str,
+    group: u32,
+    binding: u32,
+    shader_defs: &[String],
+) -> StructLayout {
+    let root = load_shader(shader);
+    let mut shaders = HashMap::default();
+    let mut import_handles = HashMap::default();
+    load_imports(&root, &mut shaders, &mut import_handles);
+    let processed = ShaderProcessor::default()
+        .process(&root, shader_defs, &shaders, &import_handles)
+        .unwrap_or_else(|err| panic!("can't preprocess {}: {:?}", shader, err));
+    let source = processed
+        .get_wgsl_source()
+        .expect("a wgsl shader stays wgsl after preprocessing");
+    let module = naga::front::wgsl::parse_str(source).unwrap_or_else(|err| {
+        panic!(
+            "{} is not valid wgsl:\n{}",
+            shader,
+            err.emit_to_string(source)
+        )
+    });
+
+    let variable = module
+        .global_variables
+        .iter()
+        .map(|(_, variable)| variable)
+        .find(|variable| {
+            variable
+                .binding
+                .as_ref()
+                .map_or(false, |b| b.group == group && b.binding == binding)
+        })
+        .unwrap_or_else(|| {
+            panic!(
+                "{} has nothing at @group({}) @binding({})",
+                shader, group, binding
+            )
+        });
+
+    match &module.types[variable.ty].inner {
+        naga::TypeInner::Struct { members, span } => StructLayout {
+            size: *span as u64,
+            offsets: members.iter().map(|member| member.offset as u64).collect(),
+        },
+        other => panic!(
+            "{} @group({}) @binding({}) is {:?}, not a struct",
+            shader, group, binding, other
+        ),
+    }
+}
+
+// Layout encase uses when writing `T` into a uniform buffer
+pub fn rust_uniform_layout<T, const N: usize>() -> StructLayout
+where
+    T: ShaderType<ExtraMetadata = StructMetadata<N>>,
+{
+    StructLayout {
+        size: T::min_size().get(),
+        offsets: T::METADATA.extra.offsets.to_vec(),
+    }
+}
+
+// `@location` and byte size of every member of the struct `name` in an asset
+// shader. Only the struct itself is parsed, so shaders that import bevy's own
+// modules work too. Only main_b and the mask texture of main have vertex
+// buffers of their own
+#[allow(dead_code)]
+pub fn wgsl_struct_locations(shader: &str, name: &str) -> Vec<(u32, u64)> {
+    let path = asset_path(shader);
+    let source =
+        fs::read_to_string(&path).unwrap_or_else(|err| panic!("can't read {}: {}", path, err));
+    let start = source
+        .find(&format!("struct {} {{", name))
+        .unwrap_or_else(|| panic!("{} has no struct {}", shader, name));
+    let end = start + source[start..].find('}').expect("structs are closed") + 1;
+    let module = naga::front::wgsl::parse_str(&source[start..end])
+        .unwrap_or_else(|err| panic!("struct {} in {} is not valid wgsl: {}", name, shader, err));
+
+    let members = module
+        .types
+        .iter()
+        .find_map(|(_, ty)| match &ty.inner {
+            naga::TypeInner::Struct { members, .. } if ty.name.as_deref() == Some(name) => {
+                Some(members)
+            }
+            _ => None,
+        })
+        .expect("the struct was just parsed");
+    members
+        .iter()
+        .filter_map(|member| match member.binding {
+            Some(naga::Binding::Location { location, .. }) => Some((
+                location,
+                module.types[member.ty].inner.size(&module.constants) as u64,
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+// `assert_uniform_matches_at` for a material's uniform at `@group(1)`
+pub fn assert_uniform_matches<T, const N: usize>(shader: &str, binding: u32, shader_defs: &[String])
+where
+    T: ShaderType<ExtraMetadata = StructMetadata<N>>,
+{
+    assert_uniform_matches_at::<T, N>(shader, 1, binding, shader_defs);
+}
+
+pub fn assert_uniform_matches_at<T, const N: usize>(
+    shader: &str,
+    group: u32,
+    binding: u32,
+    shader_defs: &[String],
+) where
+    T: ShaderType<ExtraMetadata = StructMetadata<N>>,
+{
+    assert_eq!(
+        rust_uniform_layout::<T, N>(),
+        wgsl_uniform_layout(shader, group, binding, shader_defs),
+        "{} does not match @group({}) @binding({}) in {} with {:?}",
+        std::any::type_name::<T>(),
+        group,
+        binding,
+        shader,
+        shader_defs
+    );
+}
+
+// Checks that assets/`path` is what `T` generates, or rewrites it when
+// UPDATE_WGSL is set
+pub fn assert_generated_wgsl<T: WgslStruct>(path: &str) {
+    let generated = T::wgsl_module();
+    let full_path = asset_path(path);
+    if env::var_os("UPDATE_WGSL").is_some() {
+        fs::write(&full_path, &generated)
+            .unwrap_or_else(|err| panic!("can't write {}: {}", full_path, err));
+        return;
+    }
+    let current = fs::read_to_string(&full_path).unwrap_or_default();
+    assert!(
+        current == generated,
+        "{} is out of date with {}, run `UPDATE_WGSL=1 cargo test`",
+        path,
+        T::NAME
+    );
+}
@@ -0,0 +1,466 @@
+// Instanced path for drawing many textured, tinted sprites in one draw call.
+//
+// `InstancedSprites` holds one quad mesh plus a list of per-instance values:
+// position and scale, color and the `time` value that my_material_b.wgsl
+// reads from its uniform. The whole list goes into a vertex buffer stepped
+// per instance, so a batch is one entity, one bind group and one draw no
+// matter how many sprites it holds. instanced_sprite_b.wgsl does the same
+// fragment math as my_material_b.wgsl.
+//
+// The render world keeps every batch's vertex buffer between frames. Only a
+// changed `InstancedSprites` is copied out of the main world and written into
+// it, the buffer only grows when the batch does. The texture bind group is kept
+// the same way and only rebuilt when the batch's image or its GPU texture
+// changes.
+
+use bevy::{
+    core_pipeline::core_2d::Transparent2d,
+    ecs::system::{lifetimeless::*, SystemParamItem},
+    prelude::*,
+    render::{
+        mesh::{GpuBufferInfo, MeshVertexBufferLayout},
+        render_asset::RenderAssets,
+        render_phase::{
+            AddRenderCommand, DrawFunctions, EntityRenderCommand, RenderCommandResult, RenderPhase,
+            SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        texture::GpuImage,
+        view::VisibleEntities,
+        Extract, RenderApp, RenderStage,
+    },
+    sprite::{
+        Mesh2dHandle, Mesh2dPipeline, Mesh2dPipelineKey, Mesh2dUniform, SetMesh2dBindGroup,
+        SetMesh2dViewBindGroup,
+    },
+    utils::{FloatOrd, HashMap},
+};
+use bytemuck::{Pod, Zeroable};
+
+pub struct SpriteInstancingPlugin;
+
+impl Plugin for SpriteInstancingPlugin {
+    fn build(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .add_render_command::<Transparent2d, DrawInstancedSprites>()
+            .init_resource::<InstancedSpritePipeline>()
+            .init_resource::<SpecializedMeshPipelines<InstancedSpritePipeline>>()
+            .init_resource::<InstanceBuffers>()
+            .add_system_to_stage(RenderStage::Extract, extract_instanced_sprites)
+            .add_system_to_stage(RenderStage::Prepare, prepare_instance_buffers)
+            .add_system_to_stage(RenderStage::Queue, queue_instanced_sprite_bind_groups)
+            .add_system_to_stage(RenderStage::Queue, queue_instanced_sprites);
+    }
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct SpriteInstance {
+    // xyz is the world position, w scales the mesh
+    pub position_scale: [f32; 4],
+    // linear rgba, like the color the uniform of my_material_b.wgsl receives
+    pub color: [f32; 4],
+    pub time: f32,
+}
+
+impl SpriteInstance {
+    pub fn new(position: Vec3, scale: f32, color: Color, time: f32) -> Self {
+        Self {
+            position_scale: position.extend(scale).to_array(),
+            color: color.as_linear_rgba_f32(),
+            time,
+        }
+    }
+}
+
+// Spawn next to a `Mesh2dHandle` and a `SpatialBundle`
+#[derive(Component, Clone)]
+pub struct InstancedSprites {
+    pub image: Handle<Image>,
+    pub instances: Vec<SpriteInstance>,
+}
+
+// Marks the render entity of a batch, its instances live in `InstanceBuffers`
+#[derive(Component)]
+struct ExtractedInstancedSprites;
+
+struct InstanceBuffer {
+    image: Handle<Image>,
+    instances: BufferVec<SpriteInstance>,
+    // instances not written to the GPU yet
+    dirty: bool,
+    bind_group: Option<ImageBindGroup>,
+}
+
+// A bind group with the texture view and sampler it was made from, a reloaded
+// image gets new ones
+struct ImageBindGroup {
+    texture_view: TextureViewId,
+    sampler: SamplerId,
+    bind_group: BindGroup,
+}
+
+// Lives in the render world, keyed by main world entity
+#[derive(Default)]
+struct InstanceBuffers {
+    entries: HashMap<Entity, InstanceBuffer>,
+}
+
+#[derive(Component)]
+struct InstancedSpriteBindGroup(BindGroup);
+
+fn extract_instanced_sprites(
+    mut commands: Commands,
+    mut buffers: ResMut<InstanceBuffers>,
+    sprites_query: Extract<Query<Entity, With<InstancedSprites>>>,
+    changed_query: Extract<Query<(Entity, &InstancedSprites), Changed<InstancedSprites>>>,
+) {
+    // despawned batches, or ones that lost the component
+    buffers
+        .entries
+        .retain(|entity, _| sprites_query.get(*entity).is_ok());
+
+    for (entity, sprites) in changed_query.iter() {
+        let entry = buffers
+            .entries
+            .entry(entity)
+            .or_insert_with(|| InstanceBuffer {
+                image: Handle::default(),
+                instances: BufferVec::new(BufferUsages::VERTEX),
+                dirty: true,
+                bind_group: None,
+            });
+        if entry.image != sprites.image {
+            entry.image = sprites.image.clone_weak();
+            entry.bind_group = None;
+        }
+        entry.instances.clear();
+        for instance in &sprites.instances {
+            entry.instances.push(*instance);
+        }
+        entry.dirty = true;
+    }
+
+    for entity in sprites_query.iter() {
+        commands
+            .get_or_spawn(entity)
+            .insert(ExtractedInstancedSprites);
+    }
+}
+
+fn prepare_instance_buffers(
+    mut buffers: ResMut<InstanceBuffers>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    for entry in buffers.entries.values_mut() {
+        if entry.dirty {
+            entry.instances.write_buffer(&render_device, &render_queue);
+            entry.dirty = false;
+        }
+    }
+}
+
+fn queue_instanced_sprite_bind_groups(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    pipeline: Res<InstancedSpritePipeline>,
+    images: Res<RenderAssets<Image>>,
+    mut buffers: ResMut<InstanceBuffers>,
+) {
+    for (&entity, entry) in buffers.entries.iter_mut() {
+        let gpu_image: &GpuImage = match images.get(&entry.image) {
+            Some(gpu_image) => gpu_image,
+            None => continue,
+        };
+        let up_to_date = entry.bind_group.as_ref().map_or(false, |cached| {
+            cached.texture_view == gpu_image.texture_view.id()
+                && cached.sampler == gpu_image.sampler.id()
+        });
+        if !up_to_date {
+            let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+                label: Some("instanced_sprite_bind_group"),
+                layout: &pipeline.texture_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(&gpu_image.texture_view),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::Sampler(&gpu_image.sampler),
+                    },
+                ],
+            });
+            entry.bind_group = Some(ImageBindGroup {
+                texture_view: gpu_image.texture_view.id(),
+                sampler: gpu_image.sampler.id(),
+                bind_group,
+            });
+        }
+        // render entities are cleared every frame, the bind group is not
+        if let Some(cached) = &entry.bind_group {
+            commands
+                .get_or_spawn(entity)
+                .insert(InstancedSpriteBindGroup(cached.bind_group.clone()));
+        }
+    }
+}
+
+fn queue_instanced_sprites(
+    transparent_2d_draw_functions: Res<DrawFunctions<Transparent2d>>,
+    instanced_pipeline: Res<InstancedSpritePipeline>,
+    msaa: Res<Msaa>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancedSpritePipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    sprites_query: Query<(&Mesh2dHandle, &Mesh2dUniform), With<ExtractedInstancedSprites>>,
+    mut views: Query<(&VisibleEntities, &mut RenderPhase<Transparent2d>)>,
+) {
+    let draw_function = transparent_2d_draw_functions
+        .read()
+        .get_id::<DrawInstancedSprites>()
+        .unwrap();
+    let msaa_key = Mesh2dPipelineKey::from_msaa_samples(msaa.samples);
+
+    for (visible_entities, mut transparent_phase) in views.iter_mut() {
+        for &entity in &visible_entities.entities {
+            let (mesh_handle, mesh_uniform) = match sprites_query.get(entity) {
+                Ok(sprites) => sprites,
+                Err(_) => continue,
+            };
+            let mesh = match render_meshes.get(&mesh_handle.0) {
+                Some(mesh) => mesh,
+                None => continue,
+            };
+            let key =
+                msaa_key | Mesh2dPipelineKey::from_primitive_topology(mesh.primitive_topology);
+            let pipeline = match pipelines.specialize(
+                &mut pipeline_cache,
+                &instanced_pipeline,
+                key,
+                &mesh.layout,
+            ) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    error!("{}", err);
+                    continue;
+                }
+            };
+            transparent_phase.add(Transparent2d {
+                sort_key: FloatOrd(mesh_uniform.transform.w_axis.z),
+                entity,
+                pipeline,
+                draw_function,
+                batch_range: None,
+            });
+        }
+    }
+}
+
+pub struct InstancedSpritePipeline {
+    shader: Handle<Shader>,
+    mesh2d_pipeline: Mesh2dPipeline,
+    texture_layout: BindGroupLayout,
+}
+
+impl FromWorld for InstancedSpritePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let shader = world
+            .resource::<AssetServer>()
+            .load("instanced_sprite_b.wgsl");
+        let render_device = world.resource::<RenderDevice>();
+        let texture_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("instanced_sprite_texture_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+
+        Self {
+            shader,
+            mesh2d_pipeline: world.resource::<Mesh2dPipeline>().clone(),
+            texture_layout,
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for InstancedSpritePipeline {
+    type Key = Mesh2dPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.mesh2d_pipeline.specialize(key, layout)?;
+        descriptor.vertex.shader = self.shader.clone();
+        descriptor.vertex.buffers.push(VertexBufferLayout {
+            array_stride: std::mem::size_of::<SpriteInstance>() as u64,
+            step_mode: VertexStepMode::Instance,
+            attributes: instance_attributes(),
+        });
+        descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
+        descriptor.layout = Some(vec![
+            self.mesh2d_pipeline.view_layout.clone(),
+            self.mesh2d_pipeline.mesh_layout.clone(),
+            self.texture_layout.clone(),
+        ]);
+        Ok(descriptor)
+    }
+}
+
+// Locations 0..5 belong to the mesh attributes of the 2d mesh pipeline
+fn instance_attributes() -> Vec<VertexAttribute> {
+    vec![
+        VertexAttribute {
+            format: VertexFormat::Float32x4,
+            offset: 0,
+            shader_location: 5,
+        },
+        VertexAttribute {
+            format: VertexFormat::Float32x4,
+            offset: VertexFormat::Float32x4.size(),
+            shader_location: 6,
+        },
+        VertexAttribute {
+            format: VertexFormat::Float32,
+            offset: VertexFormat::Float32x4.size() * 2,
+            shader_location: 7,
+        },
+    ]
+}
+
+type DrawInstancedSprites = (
+    SetItemPipeline,
+    SetMesh2dViewBindGroup<0>,
+    SetMesh2dBindGroup<1>,
+    SetInstancedSpriteBindGroup<2>,
+    DrawSpriteInstances,
+);
+
+struct SetInstancedSpriteBindGroup<const I: usize>;
+
+impl<const I: usize> EntityRenderCommand for SetInstancedSpriteBindGroup<I> {
+    type Param = SQuery<Read<InstancedSpriteBindGroup>>;
+
+    fn render<'w>(
+        _view: Entity,
+        item: Entity,
+        bind_group_query: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        // the texture may still be loading
+        match bind_group_query.get_inner(item) {
+            Ok(bind_group) => {
+                pass.set_bind_group(I, &bind_group.0, &[]);
+                RenderCommandResult::Success
+            }
+            Err(_) => RenderCommandResult::Failure,
+        }
+    }
+}
+
+struct DrawSpriteInstances;
+
+impl EntityRenderCommand for DrawSpriteInstances {
+    type Param = (
+        SRes<RenderAssets<Mesh>>,
+        SQuery<Read<Mesh2dHandle>>,
+        SRes<InstanceBuffers>,
+    );
+
+    fn render<'w>(
+        _view: Entity,
+        item: Entity,
+        (meshes, mesh_query, buffers): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let mesh_handle = match mesh_query.get(item) {
+            Ok(mesh_handle) => mesh_handle,
+            Err(_) => return RenderCommandResult::Failure,
+        };
+        let instances = match buffers.into_inner().entries.get(&item) {
+            Some(entry) => &entry.instances,
+            None => return RenderCommandResult::Failure,
+        };
+        // nothing written yet, or an empty batch
+        let instance_buffer = match instances.buffer() {
+            Some(buffer) => buffer,
+            None => return RenderCommandResult::Failure,
+        };
+        let gpu_mesh = match meshes.into_inner().get(&mesh_handle.0) {
+            Some(gpu_mesh) => gpu_mesh,
+            None => return RenderCommandResult::Failure,
+        };
+
+        pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+        pass.set_vertex_buffer(1, instance_buffer.slice(..));
+        let instances = 0..instances.len() as u32;
+        match &gpu_mesh.buffer_info {
+            GpuBufferInfo::Indexed {
+                buffer,
+                index_format,
+                count,
+            } => {
+                pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+                pass.draw_indexed(0..*count, 0, instances);
+            }
+            GpuBufferInfo::NonIndexed { vertex_count } => {
+                pass.draw(0..*vertex_count, instances);
+            }
+        }
+        RenderCommandResult::Success
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::wgsl_struct_locations;
+
+    #[test]
+    fn instance_layout_matches_shader() {
+        assert_eq!(std::mem::size_of::<SpriteInstance>(), 36);
+
+        let instance = SpriteInstance::zeroed();
+        let base = &instance as *const SpriteInstance as usize;
+        let field_offsets = [
+            &instance.position_scale as *const [f32; 4] as usize - base,
+            &instance.color as *const [f32; 4] as usize - base,
+            &instance.time as *const f32 as usize - base,
+        ]
+        .map(|offset| offset as u64);
+
+        let inputs: HashMap<u32, u64> = wgsl_struct_locations("instanced_sprite_b.wgsl", "Vertex")
+            .into_iter()
+            .collect();
+        let attributes = instance_attributes();
+        assert_eq!(attributes.len(), field_offsets.len());
+        for (attribute, field_offset) in attributes.iter().zip(field_offsets) {
+            assert_eq!(attribute.offset, field_offset);
+            assert_eq!(
+                inputs.get(&attribute.shader_location),
+                Some(&attribute.format.size()),
+                "@location({}) of instanced_sprite_b.wgsl",
+                attribute.shader_location
+            );
+        }
+    }
+}
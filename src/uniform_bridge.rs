@@ -0,0 +1,198 @@
+// Copies a component into the uniform buffer of the entity's material.
+//
+// `UniformBridgePlugin::<C, M>` replaces the extract/prepare pair every
+// animated material used to write by hand: any entity with a `C` and a
+// `Handle<M>` gets `C` encased into `#[uniform(binding)]` of its material.
+// `C` is laid out by its own `ShaderType` derive, so its fields have to match
+// the uniform struct of the shader.
+// `M` says which of its bindings are uniforms through `UniformBindings`, which
+// finds the buffer behind textured materials too.
+//
+// Only changed components are extracted. The render world keeps the last value
+// of every entity and uploads it again when the material was not prepared yet
+// or got a fresh buffer because the asset changed. Uploads that only touch a
+// few bytes, like the mask's dirty ranges, still need their own systems.
+
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{encase, encase::internal::WriteInto, BufferId, ShaderType},
+        renderer::RenderQueue,
+        Extract, RenderApp, RenderStage,
+    },
+    sprite::{Material2d, RenderMaterials2d},
+    utils::HashMap,
+};
+
+use crate::material_binding::{uniform_buffer, BindingErrorLog, UniformBindings};
+
+pub struct UniformBridgePlugin<C, M> {
+    binding: u32,
+    marker: PhantomData<fn() -> (C, M)>,
+}
+
+impl<C, M> UniformBridgePlugin<C, M> {
+    // Writes into `#[uniform(binding)]` instead of `#[uniform(0)]`
+    pub fn new(binding: u32) -> Self {
+        Self {
+            binding,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<C, M> Default for UniformBridgePlugin<C, M> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<C, M> Plugin for UniformBridgePlugin<C, M>
+where
+    C: Component + ShaderType + WriteInto + Clone,
+    M: Material2d + UniformBindings,
+{
+    fn build(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .insert_resource(BridgedUniforms::<C, M>::new(self.binding))
+            .add_system_to_stage(RenderStage::Extract, extract_bridged_uniforms::<C, M>)
+            .add_system_to_stage(RenderStage::Prepare, prepare_bridged_uniforms::<C, M>);
+    }
+}
+
+struct BridgedUniform<C, M: Material2d> {
+    value: C,
+    handle: Handle<M>,
+    // None until the value reaches the GPU, a re-prepared material gets a
+    // fresh buffer that needs the value again
+    uploaded_to: Option<BufferId>,
+}
+
+// Lives in the render world, keyed by main world entity
+struct BridgedUniforms<C, M: Material2d> {
+    binding: u32,
+    entries: HashMap<Entity, BridgedUniform<C, M>>,
+    scratch: encase::UniformBuffer<Vec<u8>>,
+}
+
+impl<C, M: Material2d> BridgedUniforms<C, M> {
+    fn new(binding: u32) -> Self {
+        Self {
+            binding,
+            entries: HashMap::default(),
+            scratch: encase::UniformBuffer::new(Vec::new()),
+        }
+    }
+}
+
+fn extract_bridged_uniforms<C, M>(
+    mut bridged: ResMut<BridgedUniforms<C, M>>,
+    bridged_query: Extract<Query<Entity, (With<C>, With<Handle<M>>)>>,
+    changed_query: Extract<Query<(Entity, &C, &Handle<M>), Or<(Changed<C>, Changed<Handle<M>>)>>>,
+) where
+    C: Component + ShaderType + WriteInto + Clone,
+    M: Material2d + UniformBindings,
+{
+    // despawned entities, or ones that lost the component or material
+    bridged
+        .entries
+        .retain(|entity, _| bridged_query.get(*entity).is_ok());
+
+    for (entity, value, handle) in changed_query.iter() {
+        bridged.entries.insert(
+            entity,
+            BridgedUniform {
+                value: value.clone(),
+                handle: handle.clone_weak(),
+                uploaded_to: None,
+            },
+        );
+    }
+}
+
+fn prepare_bridged_uniforms<C, M>(
+    mut bridged: ResMut<BridgedUniforms<C, M>>,
+    materials: Res<RenderMaterials2d<M>>,
+    render_queue: Res<RenderQueue>,
+    mut error_log: Local<BindingErrorLog<M>>,
+) where
+    C: Component + ShaderType + WriteInto + Clone,
+    M: Material2d + UniformBindings,
+{
+    let BridgedUniforms {
+        binding,
+        entries,
+        scratch,
+    } = &mut *bridged;
+    for entry in entries.values_mut() {
+        // not prepared yet, tried again next frame
+        let material = match materials.get(&entry.handle) {
+            Some(material) => material,
+            None => continue,
+        };
+        let buffer = match uniform_buffer(material, *binding) {
+            Ok(buffer) => buffer,
+            Err(err) => {
+                error_log.report(&entry.handle, &err);
+                continue;
+            }
+        };
+        if entry.uploaded_to == Some(buffer.id()) {
+            continue;
+        }
+
+        scratch.write(&entry.value).unwrap();
+        render_queue.write_buffer(buffer, 0, scratch.as_ref());
+        entry.uploaded_to = Some(buffer.id());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material_binding::uniform_binding_index;
+    use bevy::{
+        reflect::TypeUuid,
+        render::render_resource::{AsBindGroup, ShaderRef},
+    };
+
+    #[derive(Component, ShaderType, Clone)]
+    struct Tint {
+        color: Color,
+        time: f32,
+    }
+
+    // Laid out like main_w's material, the uniform is the last binding
+    #[derive(AsBindGroup, TypeUuid, Clone)]
+    #[uuid = "9b54d0e3-71c2-4a8f-b6e5-0c3f28a17d94"]
+    struct TexturedMaterial {
+        #[uniform(0)]
+        color: Color,
+        #[uniform(0)]
+        time: f32,
+        #[texture(1)]
+        #[sampler(2)]
+        image: Handle<Image>,
+    }
+
+    impl Material2d for TexturedMaterial {
+        fn fragment_shader() -> ShaderRef {
+            "my_material_w.wgsl".into()
+        }
+    }
+
+    impl UniformBindings for TexturedMaterial {}
+
+    #[test]
+    fn bridges_into_the_uniform_behind_a_texture() {
+        let bridged = BridgedUniforms::<Tint, TexturedMaterial>::new(0);
+        // uniform, texture view and sampler
+        assert_eq!(
+            uniform_binding_index::<TexturedMaterial>(bridged.binding, 3),
+            Ok(2)
+        );
+        assert!(uniform_binding_index::<TexturedMaterial>(1, 3).is_err());
+    }
+}
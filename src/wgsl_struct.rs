@@ -0,0 +1,99 @@
+// WGSL declarations generated from the Rust uniform structs.
+//
+// Declaring a uniform struct through `wgsl_struct!` keeps it a plain struct
+// with the given derives and, in tests, also records the WGSL type of every
+// field. `shader_layout::assert_generated_wgsl` turns that into
+// assets/generated/*.wgsl, which the shaders pull in with
+// `#import "generated/<name>.wgsl"` instead of repeating the struct. Field
+// order and array sizes come from the Rust side, so after changing a struct
+// run `UPDATE_WGSL=1 cargo test` to regenerate the files; a plain
+// `cargo test` fails while they are stale.
+
+macro_rules! wgsl_struct {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($field_vis:vis $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name {
+            $($field_vis $field: $ty),*
+        }
+
+        #[cfg(test)]
+        impl $crate::wgsl_struct::WgslStruct for $name {
+            const NAME: &'static str = stringify!($name);
+
+            fn wgsl_fields() -> Vec<(&'static str, String)> {
+                vec![$((
+                    stringify!($field),
+                    <$ty as $crate::wgsl_struct::WgslType>::wgsl_type(),
+                )),*]
+            }
+        }
+    };
+}
+
+pub(crate) use wgsl_struct;
+
+#[cfg(test)]
+use bevy::prelude::{Color, Mat4, Vec2, Vec3, Vec4};
+
+// How a Rust field type is spelled in WGSL, matching what encase writes
+#[cfg(test)]
+pub trait WgslType {
+    fn wgsl_type() -> String;
+}
+
+#[cfg(test)]
+macro_rules! wgsl_types {
+    ($($ty:ty => $wgsl:literal),* $(,)?) => {
+        $(impl WgslType for $ty {
+            fn wgsl_type() -> String {
+                $wgsl.to_string()
+            }
+        })*
+    };
+}
+
+#[cfg(test)]
+wgsl_types! {
+    f32 => "f32",
+    u32 => "u32",
+    i32 => "i32",
+    Vec2 => "vec2<f32>",
+    Vec3 => "vec3<f32>",
+    Vec4 => "vec4<f32>",
+    Mat4 => "mat4x4<f32>",
+    // written as linear rgba
+    Color => "vec4<f32>",
+}
+
+#[cfg(test)]
+impl<T: WgslType, const N: usize> WgslType for [T; N] {
+    fn wgsl_type() -> String {
+        format!("array<{}, {}>", T::wgsl_type(), N)
+    }
+}
+
+#[cfg(test)]
+pub trait WgslStruct {
+    const NAME: &'static str;
+
+    fn wgsl_fields() -> Vec<(&'static str, String)>;
+
+    // Contents of the generated .wgsl file
+    fn wgsl_module() -> String {
+        let mut module = format!(
+            "// Generated from `{}` by `UPDATE_WGSL=1 cargo test`, do not edit\n\nstruct {} {{\n",
+            Self::NAME,
+            Self::NAME
+        );
+        for (field, ty) in Self::wgsl_fields() {
+            module += &format!("    {}: {},\n", field, ty);
+        }
+        module += "};\n";
+        module
+    }
+}